@@ -14,22 +14,185 @@ Endpoints:
 Usage:
     python app.py
 
+Embedding:
+    from app import build_app, run
+    client = build_app({"MIN_LATENCY_MS": 0}).test_client()
+
 Environment variables:
     PORT - Port to run the server on (default: 8000)
     API_KEY - Required API key for authentication (default: sk-dummy)
 """
 
 import os
+import re
 import json
 import time
 import random
+import io
+import math
+import wave
+import base64
+import signal
+import socket
+import zlib
+import hashlib
+import logging
 import datetime
+import threading
+from collections import OrderedDict
+from concurrent.futures import ThreadPoolExecutor
 from typing import List, Dict, Any, Optional
-from flask import Flask, request, jsonify, Response
+from decimal import Decimal
+from flask import Blueprint, Flask, current_app, g, request, jsonify, Response
+from flask.json.provider import DefaultJSONProvider
+from flask.logging import default_handler, has_level_handler
 from flask_cors import CORS
 
-app = Flask(__name__)
-CORS(app)
+try:
+    import tiktoken
+except ImportError:  # Only needed for the cl100k/o200k tokenizers
+    tiktoken = None
+
+import sys
+import importlib.util
+import config as config_module
+
+class ConfigSnapshot:
+    """Stands in for the config module, so reload_config() can swap it out.
+
+    Requests read the config that was current when they started, for as long
+    as their response streams; everything else reads the current config.
+    """
+
+    def __init__(self, module):
+        object.__setattr__(self, 'current', module)
+        object.__setattr__(self, 'local', threading.local())
+
+    def active(self):
+        return getattr(self.local, 'module', None) or self.current
+
+    def bind(self, func):
+        """Wrap func to run, e.g. on a pool worker, with the caller's config."""
+        snapshot = self.active()
+
+        def run_with_snapshot(*args, **kwargs):
+            previous, self.local.module = getattr(self.local, 'module', None), snapshot
+            try:
+                return func(*args, **kwargs)
+            finally:
+                self.local.module = previous
+        return run_with_snapshot
+
+    def __getattr__(self, name: str) -> Any:
+        return getattr(self.active(), name)
+
+    def __setattr__(self, name: str, value: Any) -> None:
+        setattr(self.current, name, value)
+
+class ConfigOverlay:
+    """A config module with one app's build_app(settings) laid over it."""
+
+    def __init__(self, module, settings: Dict[str, Any]):
+        self.module = module
+        self.settings = settings
+
+    def __getattr__(self, name: str) -> Any:
+        if name in self.settings:
+            return self.settings[name]
+        return getattr(self.module, name)
+
+config = ConfigSnapshot(config_module)
+CONFIG_RELOAD_LOCK = threading.Lock()
+# Set by SIGHUP; whoever holds CONFIG_RELOAD_LOCK next runs the reload
+RELOAD_PENDING = False
+
+def load_config() -> List[str]:
+    """Re-read config.py and swap it in; the caller holds CONFIG_RELOAD_LOCK."""
+    old = config.current
+    spec = importlib.util.spec_from_file_location('config', old.__file__)
+    fresh = importlib.util.module_from_spec(spec)
+    try:
+        spec.loader.exec_module(fresh)
+    except Exception as e:
+        logger.error("Config reload failed, keeping the current config: %s: %s", type(e).__name__, e)
+        return []
+    changed = sorted(name for name in dir(fresh) if name.isupper() and not name.startswith('_')
+                     and getattr(old, name, None) != getattr(fresh, name))
+    object.__setattr__(config, 'current', fresh)
+    sys.modules['config'] = fresh
+    logger.warning("Reloaded config: %s", ', '.join(changed) if changed else "no changes")
+    return changed
+
+def run_pending_reloads() -> None:
+    """Run the reloads SIGHUP asked for, unless another reload holds the lock.
+
+    Any number of signals during a reload are merged into one more reload,
+    and the pending flag is checked again after releasing the lock so a
+    signal arriving just before the release isn't lost.
+    """
+    global RELOAD_PENDING
+    while RELOAD_PENDING and CONFIG_RELOAD_LOCK.acquire(blocking=False):
+        try:
+            while RELOAD_PENDING:
+                RELOAD_PENDING = False
+                load_config()
+        finally:
+            CONFIG_RELOAD_LOCK.release()
+
+def reload_config() -> List[str]:
+    """Re-read config.py (the environment and CONFIG_FILE) and swap it in.
+
+    Settings assigned on the config at runtime are replaced by the re-read
+    values, while an app's build_app(settings) stay laid over them. Returns
+    the names of changed settings. A config that fails to load is logged and
+    the current one kept.
+    """
+    with CONFIG_RELOAD_LOCK:
+        changed = load_config()
+    run_pending_reloads()
+    return changed
+
+def handle_sighup(signum, frame) -> None:
+    """SIGHUP handler: reload the config without dropping connections.
+
+    The handler may interrupt a reload on the same thread, so it never waits
+    for CONFIG_RELOAD_LOCK; the reload already running picks the signal up.
+    """
+    global RELOAD_PENDING
+    RELOAD_PENDING = True
+    run_pending_reloads()
+
+def format_json_numbers(text: str) -> str:
+    """Rewrite the numbers in serialized JSON to suit JSON_NUMBER_FORMAT.
+
+    "decimal" writes floats without exponents and integral floats as integers.
+    """
+    if config.JSON_NUMBER_FORMAT != 'decimal':
+        return text
+
+    def reformat(match: re.Match) -> str:
+        token = match.group(0)
+        if token.startswith('"') or not any(c in token for c in '.eE'):
+            return token
+        value = Decimal(token)
+        if value == value.to_integral_value():
+            return str(int(value))
+        return format(value, 'f')
+
+    return re.sub(r'"(?:[^"\\]|\\.)*"|-?\d+(?:\.\d+)?(?:[eE][+-]?\d+)?', reformat, text)
+
+class NumberFormattingJSONProvider(DefaultJSONProvider):
+    """Flask's JSON provider, with numbers formatted per JSON_NUMBER_FORMAT."""
+
+    def dumps(self, obj: Any, **kwargs: Any) -> str:
+        return format_json_numbers(super().dumps(obj, **kwargs))
+
+# Every API route; build_app() mounts them on a Flask app
+api = Blueprint('api', __name__)
+
+# Flask's app.logger under import, but "__main__" under `python app.py`, so
+# build_app() sets it up the same way
+logger = logging.getLogger(__name__)
 
 # Configuration
 PORT = int(os.getenv('PORT', 8000))
@@ -72,12 +235,611 @@ DUMMY_CHAT_RESPONSES = [
     "I can help you with that! Here's what I recommend based on the information provided."
 ]
 
+# Vocabulary for responses in other languages (`x-mock-lang`)
+LANGUAGE_WORDS = {
+    "es": ["hola", "gracias", "respuesta", "pregunta", "información", "ejemplo", "según",
+           "también", "análisis", "niño", "mañana", "corazón", "después", "más", "útil"],
+    "de": ["hallo", "danke", "Antwort", "Frage", "Beispiel", "Größe", "über", "schön",
+           "natürlich", "möglich", "Straße", "Lösung", "außerdem", "wichtig", "Übersicht"],
+    "ja": ["こんにちは", "ありがとう", "質問", "答え", "情報", "例えば", "説明", "日本語",
+           "テスト", "データ", "確認", "大丈夫", "文字", "提案", "結果"]
+}
+
 DUMMY_EMBEDDINGS = [
     [0.1, -0.2, 0.3, 0.4, -0.5, 0.6, -0.7, 0.8],
     [-0.9, 0.1, -0.2, 0.3, -0.4, 0.5, -0.6, 0.7],
     [0.2, 0.3, -0.4, 0.5, -0.6, 0.7, -0.8, 0.9]
 ]
 
+# Number of streams currently sending chunks
+ACTIVE_STREAMS = 0
+ACTIVE_STREAMS_LOCK = threading.Lock()
+
+# Requests that haven't finished sending their response, streams included
+IN_FLIGHT_REQUESTS = 0
+IN_FLIGHT_LOCK = threading.Lock()
+
+# Set once a shutdown signal arrives; streams still running after the grace
+# deadline are truncated
+SHUTDOWN_DEADLINE: Optional[float] = None
+FORCED_STREAMS = 0
+
+# Times a stream waited on a slow client for longer than BACKPRESSURE_LOG_MS
+BACKPRESSURE_TOTAL = 0
+BACKPRESSURE_LOCK = threading.Lock()
+
+# How many requests have drawn an outcome from ERROR_SCHEDULE
+ERROR_SCHEDULE_POSITION = 0
+ERROR_SCHEDULE_LOCK = threading.Lock()
+
+# Completion tokens generated since the process started, for LIFETIME_TOKEN_BUDGET
+GENERATED_TOKENS = 0
+GENERATED_TOKENS_LOCK = threading.Lock()
+
+# Completions created with `store: true`, keyed by id, with their ETag
+STORED_COMPLETIONS: "OrderedDict[str, tuple[Dict[str, Any], str]]" = OrderedDict()
+STORED_COMPLETIONS_LOCK = threading.Lock()
+
+# Streamed completions kept for Last-Event-ID resumption, keyed by completion id
+STREAM_SESSIONS: "OrderedDict[str, Dict[str, Any]]" = OrderedDict()
+STREAM_SESSIONS_LOCK = threading.Lock()
+
+# (param, expected type, minimum, maximum) checked by STRICT_VALIDATION
+CHAT_PARAM_RULES = [
+    ("temperature", "decimal", 0, 2),
+    ("top_p", "decimal", 0, 1),
+    ("n", "integer", 1, 128),
+    ("max_tokens", "integer", 1, None),
+    ("max_completion_tokens", "integer", 1, None),
+    ("presence_penalty", "decimal", -2, 2),
+    ("frequency_penalty", "decimal", -2, 2),
+]
+
+# What omitted sampling parameters default to; temperature defaults per model
+SAMPLING_DEFAULTS = {
+    "top_p": 1,
+    "presence_penalty": 0,
+    "frequency_penalty": 0
+}
+
+class JSONBodyError(ValueError):
+    """Raised when a request body is not a single, well-formed JSON object."""
+
+def reject_duplicate_keys(pairs: List[tuple]) -> Dict[str, Any]:
+    """object_pairs_hook that refuses objects with repeated keys."""
+    result = {}
+    for key, value in pairs:
+        if key in result:
+            raise JSONBodyError(f"duplicate key {key!r}")
+        result[key] = value
+    return result
+
+def reject_constant(name: str) -> None:
+    """parse_constant hook that refuses NaN and Infinity, which JSON forbids."""
+    raise JSONBodyError(f"invalid number {name}")
+
+def parse_json_body() -> tuple[Optional[Dict[str, Any]], Optional[tuple]]:
+    """Read the request body as a JSON object.
+
+    Returns (data, None) on success or (None, error_response) for bodies that
+    are too large, not UTF-8, not valid JSON, or not an object, so handlers
+    never see a parsing exception.
+    """
+    limit = config.MAX_REQUEST_BYTES
+    if request.content_length is not None and request.content_length > limit:
+        return None, request_too_large()
+
+    raw = request.stream.read(limit + 1)
+    if len(raw) > limit:
+        return None, request_too_large()
+    if not raw.strip():
+        return None, invalid_request("Request body is required")
+
+    try:
+        data = json.loads(raw.decode('utf-8'),
+                          object_pairs_hook=reject_duplicate_keys,
+                          parse_constant=reject_constant)
+    except (UnicodeDecodeError, ValueError, RecursionError):
+        return None, invalid_request(
+            "We could not parse the JSON body of your request. (HINT: This likely means you "
+            "aren't using your HTTP library correctly. The OpenAI API expects a JSON payload, "
+            "but what was sent was not valid JSON.)"
+        )
+
+    if not isinstance(data, dict):
+        return None, invalid_request(
+            f"Invalid type for request body: expected an object, but got "
+            f"{json_type_name(data)} instead.", None, "invalid_type"
+        )
+
+    return data, None
+
+def request_too_large():
+    """Build a 413 response for bodies over MAX_REQUEST_BYTES."""
+    return jsonify({
+        "error": {
+            "message": f"Request body exceeds the maximum size of {config.MAX_REQUEST_BYTES} bytes",
+            "type": "invalid_request_error",
+            "param": None,
+            "code": "request_too_large"
+        }
+    }), 413
+
+def message_text(message: Dict[str, Any]) -> str:
+    """Return the text of a message whose content is a string, parts or null."""
+    content = message.get('content')
+    if isinstance(content, str):
+        return content
+    if isinstance(content, list):
+        return ' '.join(part.get('text', '') for part in content
+                        if isinstance(part, dict) and isinstance(part.get('text'), str))
+    return ''
+
+AUDIO_VOICES = ('alloy', 'ash', 'ballad', 'coral', 'echo', 'fable', 'nova', 'onyx', 'sage', 'shimmer', 'verse')
+AUDIO_FORMATS = ('wav', 'aac', 'mp3', 'flac', 'opus', 'pcm16')
+
+SERVICE_TIERS = ('auto', 'default', 'flex', 'priority')
+
+# The tier each service_tier falls back to when a request is downgraded
+SERVICE_TIER_DOWNGRADES = {
+    "priority": "default",
+    "default": "flex"
+}
+
+# `x-mock-tokenizer` values backed by tiktoken encodings
+TIKTOKEN_ENCODINGS = {
+    "cl100k": "cl100k_base",
+    "o200k": "o200k_base"
+}
+
+# Size of the mock vocabulary that default-tokenizer words hash into
+MOCK_VOCAB_SIZE = 50257
+
+# Accepted range for the `model@tps=N` speed suffix
+MIN_MODEL_TPS = 0.01
+MAX_MODEL_TPS = 10000
+
+# Settings lookups read through `config`, so a build_app() app's own settings apply
+
+def get_model_settings(model_id: str) -> dict:
+    """Get settings for a specific model."""
+    return config.MODEL_SETTINGS.get(model_id, {})
+
+def get_default_max_tokens(model_id: str) -> int:
+    """Get the completion length used when a request omits max_tokens."""
+    if model_id in config.MODEL_DEFAULT_MAX:
+        return config.MODEL_DEFAULT_MAX[model_id]
+    return get_model_settings(model_id).get("default_max_tokens", config.DEFAULT_MAX_TOKENS)
+
+def get_default_temperature(model_id: str) -> float:
+    """Get the temperature used when a request omits it."""
+    if model_id in config.MODEL_DEFAULT_TEMPERATURE:
+        return config.MODEL_DEFAULT_TEMPERATURE[model_id]
+    return get_model_settings(model_id).get("default_temperature", 0.7)
+
+def get_error_rate(route: str) -> float:
+    """Get the injected error rate for a route ("chat", "embeddings", "models")."""
+    return config.ROUTE_ERROR_RATES.get(route, config.ERROR_RATE)
+
+def get_processing_delay(model_id: str) -> float:
+    """Get a chat model's processing delay, scaled by its multiplier."""
+    base_delay = random.uniform(
+        config.TIMING_SETTINGS["chat_completion_min_delay"],
+        config.TIMING_SETTINGS["chat_completion_max_delay"]
+    )
+    return base_delay * config.MODEL_PROCESSING_MULTIPLIERS.get(model_id, 1.0)
+
+def invalid_request(message: str, param: Optional[str] = None, code: Optional[str] = None):
+    """Build a 400 response in the OpenAI error format."""
+    return jsonify({
+        "error": {
+            "message": message,
+            "type": "invalid_request_error",
+            "param": param,
+            "code": code
+        }
+    }), 400
+
+def json_type_name(value: Any) -> str:
+    """Name a JSON value's type the way OpenAI error messages do."""
+    if value is None:
+        return "null"
+    if isinstance(value, bool):
+        return "boolean"
+    if isinstance(value, int):
+        return "integer"
+    if isinstance(value, float):
+        return "decimal"
+    if isinstance(value, str):
+        return "string"
+    if isinstance(value, list):
+        return "array"
+    return "object"
+
+def invalid_type(param: str, expected: str, value: Any) -> tuple:
+    """Build the (message, param, code) triple for a mistyped parameter."""
+    def with_article(name: str) -> str:
+        return f"{'an' if name[0] in 'aeiou' else 'a'} {name}"
+
+    return (f"Invalid type for '{param}': expected {with_article(expected)}, but got "
+            f"{with_article(json_type_name(value))} instead.", param, "invalid_type")
+
+def validate_param_range(data: Dict[str, Any], param: str, expected: str,
+                         minimum: Optional[float], maximum: Optional[float]) -> Optional[tuple]:
+    """Check one numeric parameter, returning (message, param, code) if invalid."""
+    if param not in data or data[param] is None:
+        return None
+
+    value = data[param]
+    allowed = ("integer",) if expected == "integer" else ("integer", "decimal")
+    if json_type_name(value) not in allowed:
+        return invalid_type(param, expected, value)
+
+    if minimum is not None and value < minimum:
+        return (f"Invalid '{param}': {expected} below minimum value. Expected a value >= "
+                f"{minimum}, but got {value} instead.", param, f"{expected}_below_min_value")
+    if maximum is not None and value > maximum:
+        return (f"Invalid '{param}': {expected} above maximum value. Expected a value <= "
+                f"{maximum}, but got {value} instead.", param, f"{expected}_above_max_value")
+    return None
+
+def validate_chat_request(data: Dict[str, Any]) -> Optional[tuple]:
+    """Validate a chat completion body, returning (message, param, code) on error."""
+    if 'model' in data and not isinstance(data['model'], str):
+        return invalid_type("model", "string", data['model'])
+
+    messages = data.get('messages')
+    if not messages:
+        return "Missing required parameter: 'messages'.", "messages", "missing_required_parameter"
+    if not isinstance(messages, list):
+        return invalid_type("messages", "array", messages)
+    for i, message in enumerate(messages):
+        if not isinstance(message, dict):
+            return invalid_type(f"messages[{i}]", "object", message)
+
+    tools = data.get('tools')
+    if tools is not None and not isinstance(tools, list):
+        return invalid_type("tools", "array", tools)
+    if any(not isinstance(tool, dict) for tool in tools or []):
+        return ("Invalid type for 'tools': expected an array of objects.", "tools", "invalid_type")
+    for i, tool in enumerate(tools or []):
+        if tool.get('type') != 'function':
+            continue
+        function = tool.get('function')
+        if not isinstance(function, dict):
+            return invalid_type(f"tools[{i}].function", "object", function)
+        if not isinstance(function.get('name'), str):
+            return (f"Missing required parameter: 'tools[{i}].function.name'.",
+                    f"tools[{i}].function", "missing_required_parameter")
+    if data.get('tool_choice') == 'required' and tools and \
+            not any(tool.get('type') == 'function' for tool in tools):
+        return ("Invalid value for 'tool_choice': 'required' needs at least one function in 'tools'.",
+                "tool_choice", "invalid_value")
+    functions = data.get('functions')
+    if functions is not None and (not isinstance(functions, list) or
+                                  any(not isinstance(f, dict) for f in functions)):
+        return ("Invalid type for 'functions': expected an array of objects.", "functions", "invalid_type")
+    if data.get('tool_choice') not in (None, 'none') and not tools:
+        return ("Invalid value for 'tool_choice': 'tool_choice' is only allowed when 'tools' are specified.",
+                "tool_choice", None)
+
+    prediction = data.get('prediction')
+    if prediction is not None:
+        if not isinstance(prediction, dict):
+            return invalid_type("prediction", "object", prediction)
+        if prediction.get('type') != 'content':
+            return (f"Invalid value for 'prediction.type': expected 'content', got "
+                    f"{json.dumps(prediction.get('type'))}.", "prediction.type", "invalid_value")
+        if not isinstance(prediction.get('content'), (str, list)):
+            return invalid_type("prediction.content", "string or array", prediction.get('content'))
+
+    modalities = data.get('modalities')
+    if modalities is not None:
+        if not isinstance(modalities, list):
+            return invalid_type("modalities", "array", modalities)
+        for i, modality in enumerate(modalities):
+            if modality not in ('text', 'audio'):
+                return (f"Invalid value: {json.dumps(modality)}. Supported values are: 'text' and 'audio'.",
+                        f"modalities[{i}]", "invalid_value")
+
+    audio = data.get('audio')
+    if audio is not None:
+        if not isinstance(audio, dict):
+            return invalid_type("audio", "object", audio)
+        if audio.get('voice', 'alloy') not in AUDIO_VOICES:
+            return (f"Invalid value: {json.dumps(audio.get('voice'))}. Supported values are: "
+                    f"{', '.join(repr(v) for v in AUDIO_VOICES)}.", "audio.voice", "invalid_value")
+        if audio.get('format', 'wav') not in AUDIO_FORMATS:
+            return (f"Invalid value: {json.dumps(audio.get('format'))}. Supported values are: "
+                    f"{', '.join(repr(f) for f in AUDIO_FORMATS)}.", "audio.format", "invalid_value")
+
+    if data.get('service_tier') is not None and data['service_tier'] not in SERVICE_TIERS:
+        return (f"Invalid value: {json.dumps(data['service_tier'])}. Supported values are: "
+                f"{', '.join(repr(t) for t in SERVICE_TIERS)}.", "service_tier", "invalid_value")
+
+    if 'max_tokens' in data and 'max_completion_tokens' in data \
+            and data.get('model') in config.MAX_COMPLETION_TOKENS_ONLY_MODELS:
+        return ("Setting 'max_tokens' and 'max_completion_tokens' at the same time is not supported. "
+                "Use 'max_completion_tokens' instead.", "max_tokens", "unsupported_parameter")
+
+    stop = data.get('stop')
+    if stop is not None and not isinstance(stop, (str, list)):
+        return invalid_type("stop", "string or array", stop)
+
+    seed = data.get('seed')
+    if seed is not None and (not isinstance(seed, int) or isinstance(seed, bool)):
+        return invalid_type("seed", "integer", seed)
+
+    error = validate_param_range(data, "top_logprobs", "integer", 0, 20)
+    if error:
+        return error
+    if data.get('top_logprobs') is not None and data.get('logprobs') is not True:
+        return ("Invalid value for 'top_logprobs': 'logprobs' must be set to true when "
+                "'top_logprobs' is specified.", "top_logprobs", "invalid_value")
+
+    if not config.STRICT_VALIDATION:
+        return None
+
+    for i, message in enumerate(messages):
+        if 'role' not in message:
+            return (f"Missing required parameter: 'messages[{i}].role'.",
+                    f"messages[{i}].role", "missing_required_parameter")
+        if message['role'] not in ('system', 'developer', 'user', 'assistant', 'tool', 'function'):
+            return (f"Invalid value: '{message['role']}'. Supported values are: 'system', 'developer', "
+                    f"'user', 'assistant', 'tool', and 'function'.", f"messages[{i}].role", "invalid_value")
+
+    for rule in CHAT_PARAM_RULES:
+        error = validate_param_range(data, *rule)
+        if error:
+            return error
+
+    if 'stream' in data and not isinstance(data['stream'], bool):
+        return invalid_type("stream", "boolean", data['stream'])
+
+    if isinstance(stop, list) and len(stop) > 4:
+        return (f"Invalid 'stop': array too long. Expected an array with maximum length 4, "
+                f"but got an array with length {len(stop)} instead.", "stop", "array_above_max_length")
+
+    return None
+
+class TokenBucket:
+    """Tokens-per-minute budget, topped up by a background refiller thread.
+
+    Capacity and refill rate are read from config on every use, so changes
+    to RATE_LIMIT_SETTINGS take effect immediately.
+    """
+
+    def __init__(self):
+        self.lock = threading.Lock()
+        self.tokens = float(self.capacity())
+        self.last_refill = time.monotonic()
+        self.refiller: Optional[threading.Thread] = None
+        self.watchdog: Optional[threading.Thread] = None
+        # Bumped to retire a refiller; each one exits once its generation is stale
+        self.generation = 0
+        self.refiller_started = 0.0
+        self.stalled = False
+
+    @staticmethod
+    def capacity() -> int:
+        return config.RATE_LIMIT_SETTINGS["tokens_per_minute"]
+
+    @staticmethod
+    def refill_interval() -> float:
+        return config.RATE_LIMIT_REFILL_INTERVAL_MS / 1000
+
+    def per_second(self) -> float:
+        return self.capacity() / 60
+
+    def refill(self) -> None:
+        """Add the tokens earned since the last refill."""
+        with self.lock:
+            now = time.monotonic()
+            self.tokens = min(self.capacity(), self.tokens + (now - self.last_refill) * self.per_second())
+            self.last_refill = now
+
+    def run_refiller(self, generation: int) -> None:
+        while self.generation == generation:
+            time.sleep(self.refill_interval())
+            self.refill()
+
+    def start_refiller(self) -> None:
+        """Start a new refiller generation. Call with the lock held."""
+        self.generation += 1
+        self.refiller_started = time.monotonic()
+        self.refiller = threading.Thread(target=self.run_refiller, args=(self.generation,),
+                                         daemon=True, name="token-bucket-refiller")
+        self.refiller.start()
+
+    def run_watchdog(self) -> None:
+        """Log (and with RATE_LIMIT_WATCHDOG_RESTART, replace) a refiller that stopped refilling."""
+        while True:
+            time.sleep(self.refill_interval())
+            if not config.RATE_LIMIT_WATCHDOG:
+                continue
+            with self.lock:
+                stale = time.monotonic() - max(self.last_refill, self.refiller_started)
+                if stale <= 2 * self.refill_interval():
+                    self.stalled = False
+                    continue
+                if not self.stalled:
+                    logger.error("Token bucket refiller has not run for %.0fms (interval %.0fms)%s",
+                                 stale * 1000, self.refill_interval() * 1000,
+                                 "; restarting it" if config.RATE_LIMIT_WATCHDOG_RESTART else "")
+                self.stalled = True
+            if config.RATE_LIMIT_WATCHDOG_RESTART:
+                # Catch up on the tokens the stalled refiller missed, then replace it
+                self.refill()
+                with self.lock:
+                    self.start_refiller()
+
+    def ensure_refiller(self) -> None:
+        """Start the refiller and its watchdog if they aren't running."""
+        with self.lock:
+            if self.refiller is None or not self.refiller.is_alive():
+                self.start_refiller()
+            if self.watchdog is None or not self.watchdog.is_alive():
+                self.watchdog = threading.Thread(target=self.run_watchdog, daemon=True,
+                                                 name="token-bucket-watchdog")
+                self.watchdog.start()
+
+    def try_acquire(self, cost: int) -> tuple[bool, float]:
+        """Take cost tokens, or return (False, seconds until they'll be available)."""
+        # Only the refiller adds tokens; a non-positive cost takes none
+        cost = max(cost, 0)
+        self.ensure_refiller()
+        with self.lock:
+            if self.tokens >= cost:
+                self.tokens -= cost
+                return True, 0.0
+            deficit = cost - self.tokens
+        # Allow one refill tick of slack, since tokens arrive in ticks
+        return False, deficit / self.per_second() + self.refill_interval()
+
+    def remaining(self) -> int:
+        with self.lock:
+            return int(self.tokens)
+
+TOKEN_BUCKET = TokenBucket()
+
+class EventRateLimiter:
+    """Spaces SSE events from all streams at most 1/MAX_SSE_EVENTS_PER_SEC apart."""
+
+    def __init__(self):
+        self.lock = threading.Lock()
+        self.next_slot = 0.0
+
+    def wait(self) -> None:
+        """Block until the caller may send its next event."""
+        rate = config.MAX_SSE_EVENTS_PER_SEC
+        if rate <= 0:
+            return
+        with self.lock:
+            now = time.monotonic()
+            slot = max(now, self.next_slot)
+            self.next_slot = slot + 1 / rate
+        if slot > now:
+            time.sleep(slot - now)
+
+SSE_EVENT_LIMITER = EventRateLimiter()
+
+def rate_limit_headers(response: Response, reset_seconds: float = 0.0) -> Response:
+    """Attach the x-ratelimit-*-tokens headers OpenAI sends."""
+    response.headers['x-ratelimit-limit-tokens'] = str(TOKEN_BUCKET.capacity())
+    response.headers['x-ratelimit-remaining-tokens'] = str(TOKEN_BUCKET.remaining())
+    response.headers['x-ratelimit-reset-tokens'] = f"{reset_seconds:.3f}s"
+    return response
+
+def check_token_rate_limit(cost: int, model: str) -> Optional[Response]:
+    """Reserve cost tokens, returning a 429 response if the bucket can't cover them."""
+    capacity = TOKEN_BUCKET.capacity()
+    if cost > capacity:
+        response = jsonify({"error": {
+            "message": f"Request too large for {model}: Limit {capacity}, Requested {cost}. "
+                       f"The input or output tokens must be reduced in order to run successfully.",
+            "type": "tokens", "param": None, "code": "rate_limit_exceeded"
+        }})
+        response.status_code = 429
+        return rate_limit_headers(response)
+
+    acquired, wait = TOKEN_BUCKET.try_acquire(cost)
+    if acquired:
+        return None
+
+    response = jsonify({"error": {
+        "message": f"Rate limit reached for {model} on tokens per min (TPM): Limit {capacity}, "
+                   f"Used {capacity - TOKEN_BUCKET.remaining()}, Requested {cost}. "
+                   f"Please try again in {wait:.3f}s.",
+        "type": "tokens", "param": None, "code": "rate_limit_exceeded"
+    }})
+    response.status_code = 429
+    response.headers['Retry-After'] = str(math.ceil(wait))
+    response.headers['retry-after-ms'] = str(math.ceil(wait * 1000))
+    return rate_limit_headers(response, wait)
+
+def record_generated_tokens(usage: Dict[str, Any]) -> None:
+    """Count a response's completion tokens against the lifetime budget."""
+    global GENERATED_TOKENS
+    with GENERATED_TOKENS_LOCK:
+        GENERATED_TOKENS += usage["completion_tokens"]
+
+def check_lifetime_budget() -> Optional[Response]:
+    """Return a 429 insufficient_quota response once LIFETIME_TOKEN_BUDGET is spent."""
+    if not config.LIFETIME_TOKEN_BUDGET:
+        return None
+    with GENERATED_TOKENS_LOCK:
+        exhausted = GENERATED_TOKENS >= config.LIFETIME_TOKEN_BUDGET
+    if not exhausted:
+        return None
+    return jsonify({"error": {
+        "message": "You exceeded your current quota, please check your plan and billing details.",
+        "type": "insufficient_quota", "param": None, "code": "insufficient_quota"
+    }}), 429
+
+# Errors used for fault injection: status -> (message, type, code)
+INJECTED_ERRORS = {
+    429: ("Rate limit reached for requests. Please try again in 1s.", "requests", "rate_limit_exceeded"),
+    500: ("The server had an error while processing your request. Sorry about that!", "server_error", None),
+    503: ("The engine is currently overloaded, please try again later.", "server_error", None),
+}
+
+def injected_error(status: int):
+    """Build one of the INJECTED_ERRORS responses."""
+    message, error_type, code = INJECTED_ERRORS[status]
+    response = jsonify({
+        "error": {"message": message, "type": error_type, "param": None, "code": code}
+    })
+    response.status_code = status
+    if status == 429:
+        response.headers['Retry-After'] = '1'
+    return response
+
+def check_model_available(model: str) -> Optional[Response]:
+    """Return the error for a deprecated or currently unavailable model, or None."""
+    if model in config.MODEL_DEPRECATED:
+        response = jsonify({"error": {
+            "message": f"The model `{model}` has been deprecated and is no longer available.",
+            "type": "invalid_request_error",
+            "param": "model",
+            "code": "model_not_found"
+        }})
+        response.status_code = 404
+        return response
+
+    if model not in config.MODEL_UNAVAILABLE:
+        return None
+    window = config.MODEL_UNAVAILABLE[model]
+    retry_after = 60
+    if window is not None:
+        now = datetime.datetime.now(datetime.timezone.utc)
+        minute = now.hour * 60 + now.minute
+        start, end = window
+        if not (start <= minute < end if start <= end else minute >= start or minute < end):
+            return None
+        retry_after = ((end - minute) % (24 * 60)) * 60 - now.second
+
+    response = jsonify({"error": {
+        "message": f"The model `{model}` is temporarily unavailable. Please try again later.",
+        "type": "server_error",
+        "param": None,
+        "code": "model_unavailable"
+    }})
+    response.status_code = 503
+    response.headers['Retry-After'] = str(max(retry_after, 1))
+    return response
+
+def maybe_inject_error(route: str):
+    """Return the next ERROR_SCHEDULE outcome, or a random error at the route's rate."""
+    global ERROR_SCHEDULE_POSITION
+    if config.ERROR_SCHEDULE:
+        with ERROR_SCHEDULE_LOCK:
+            status = config.ERROR_SCHEDULE[ERROR_SCHEDULE_POSITION % len(config.ERROR_SCHEDULE)]
+            ERROR_SCHEDULE_POSITION += 1
+        return injected_error(status) if status else None
+    if random.random() < get_error_rate(route):
+        return injected_error(random.choice(list(INJECTED_ERRORS)))
+    return None
+
 def check_api_key() -> tuple[bool, Optional[str]]:
     """Check if the provided API key is valid."""
     auth_header = request.headers.get('Authorization', '')
@@ -90,13 +852,332 @@ def check_api_key() -> tuple[bool, Optional[str]]:
 
     return True, None
 
+def check_organization() -> Optional[tuple]:
+    """Enforce ALLOWED_ORGS/ALLOWED_PROJECTS on the OpenAI-Organization/-Project headers."""
+    organization = request.headers.get('OpenAI-Organization')
+    project = request.headers.get('OpenAI-Project')
+
+    def denied(status: int, header: str, code: str) -> tuple:
+        return jsonify({
+            "error": {
+                "message": f"{header} header should match {header.split('-')[1].lower()} for API key",
+                "type": "invalid_request_error",
+                "param": None,
+                "code": code
+            }
+        }), status
+
+    if organization is not None and config.ALLOWED_ORGS and organization not in config.ALLOWED_ORGS:
+        return denied(401, 'OpenAI-Organization', 'mismatched_organization')
+    if project is not None and config.ALLOWED_PROJECTS and project not in config.ALLOWED_PROJECTS:
+        return denied(403, 'OpenAI-Project', 'mismatched_project')
+
+    if organization is not None or project is not None:
+        logger.info("%s %s: OpenAI-Organization=%s OpenAI-Project=%s",
+                    request.method, request.path, organization, project)
+    return None
+
+def choose_tool(tools: List[Dict], tool_choice: Any) -> Optional[Dict[str, Any]]:
+    """Pick the function the assistant should call, or None to answer with text.
+
+    `"auto"` (the default) and `"none"` answer with text, `"required"` always
+    calls one of the declared tools, and `{"type": "function", ...}` calls the
+    named one.
+    """
+    functions = [t['function'] for t in tools
+                 if t.get('type') == 'function' and isinstance(t.get('function'), dict)]
+    if not functions:
+        return None
+
+    if tool_choice == 'required':
+        return random.choice(functions)
+
+    if isinstance(tool_choice, dict) and isinstance(tool_choice.get('function'), dict):
+        name = tool_choice['function'].get('name')
+        return next((f for f in functions if f.get('name') == name), None)
+
+    return None
+
+def create_dummy_arguments(schema: Dict[str, Any]) -> Any:
+    """Create placeholder values that satisfy a JSON schema."""
+    if not isinstance(schema, dict):
+        return "dummy"
+    if isinstance(schema.get('enum'), list) and schema['enum']:
+        return schema['enum'][0]
+
+    minimum = schema.get('minimum')
+    if isinstance(minimum, bool) or not isinstance(minimum, (int, float)):
+        minimum = None
+
+    schema_type = schema.get('type', 'object')
+    if schema_type == 'object':
+        properties = schema.get('properties')
+        if not isinstance(properties, dict):
+            return {}
+        return {name: create_dummy_arguments(prop) for name, prop in properties.items()}
+    if schema_type == 'array':
+        return [create_dummy_arguments(schema.get('items', {}))]
+    if schema_type == 'integer':
+        return int(minimum) if minimum is not None else 1
+    if schema_type == 'number':
+        return float(minimum) if minimum is not None else 1.0
+    if schema_type == 'boolean':
+        return True
+    return "dummy"
+
+def check_stream_negotiation(stream: bool) -> Optional[tuple]:
+    """Catch `stream` values that contradict the Accept header."""
+    accept = request.accept_mimetypes
+    if not stream and accept.best_match(['application/json', 'text/event-stream']) == 'text/event-stream':
+        return ("The Accept header asks for text/event-stream but 'stream' is false; set "
+                "'stream' to true to receive server-sent events.", "stream", "stream_negotiation_mismatch")
+    if stream and accept.provided and not accept['text/event-stream']:
+        return (f"'stream' is true but the Accept header ({request.headers.get('Accept')}) does not "
+                "allow text/event-stream; accept it or set 'stream' to false.",
+                "stream", "stream_negotiation_mismatch")
+    return None
+
+def resolve_duplicate_rate() -> tuple[float, Optional[tuple]]:
+    """Read the `x-mock-duplicate-rate` header, returning (rate, error)."""
+    value = request.headers.get('x-mock-duplicate-rate')
+    if value is None:
+        return 0.0, None
+    try:
+        rate = float(value)
+    except ValueError:
+        rate = -1.0
+    if not 0 <= rate <= 1:
+        return 0.0, (f"Invalid duplicate rate '{value}'. Expected a number between 0 and 1.",
+                     "x-mock-duplicate-rate", "invalid_value")
+    return rate, None
+
+def apply_fixed_id(response: Dict[str, Any]):
+    """Force the response id from `x-mock-fixed-id`, colliding ids across requests on purpose."""
+    fixed_id = request.headers.get('x-mock-fixed-id')
+    if fixed_id:
+        response["id"] = fixed_id
+
+def resolve_tokenizer() -> tuple[Optional[str], Optional[tuple]]:
+    """Read the `x-mock-tokenizer` header, returning (tokenizer, error)."""
+    tokenizer = request.headers.get('x-mock-tokenizer')
+    if tokenizer is None:
+        return None, None
+    if tokenizer not in ('char',) + tuple(TIKTOKEN_ENCODINGS):
+        return None, (f"Unknown tokenizer '{tokenizer}'. Expected one of: cl100k, o200k, char.",
+                      "x-mock-tokenizer", "invalid_value")
+    if tokenizer in TIKTOKEN_ENCODINGS and tiktoken is None:
+        return None, (f"Tokenizer '{tokenizer}' requires the tiktoken package.",
+                      "x-mock-tokenizer", "tokenizer_unavailable")
+    return tokenizer, None
+
+def count_tokens(text: str, tokenizer: Optional[str] = None) -> int:
+    """Count tokens with the chosen tokenizer; the default counts words."""
+    if tokenizer == 'char':
+        return len(text)
+    if tokenizer in TIKTOKEN_ENCODINGS:
+        return len(tiktoken.get_encoding(TIKTOKEN_ENCODINGS[tokenizer]).encode(text))
+    return len(text.split())
+
+def truncate_prompt(messages: List[Dict], tokenizer: Optional[str] = None) -> tuple[List[Dict], bool]:
+    """Drop the oldest non-system messages until the prompt fits PROMPT_TOKEN_LIMIT.
+
+    System and developer messages and the final message are always kept.
+    """
+    def prompt_tokens(kept: List[Dict]) -> int:
+        return sum(count_tokens(message_text(msg), tokenizer) for msg in kept)
+
+    kept = list(messages)
+    truncated = False
+    while prompt_tokens(kept) > config.PROMPT_TOKEN_LIMIT:
+        oldest = next((i for i, msg in enumerate(kept[:-1])
+                       if msg.get('role') not in ('system', 'developer')), None)
+        if oldest is None:
+            break
+        del kept[oldest]
+        truncated = True
+    return kept, truncated
+
+def create_dummy_audio(transcript: str, audio_format: str) -> Dict[str, Any]:
+    """Create a synthetic spoken version of a transcript.
+
+    The audio is a quiet tone roughly as long as the transcript would take to
+    say. `wav` gets a WAV container; other formats get raw 16-bit PCM samples.
+    """
+    sample_rate = 8000
+    duration = min(0.06 * len(transcript.split()) + 0.1, 10.0)
+    samples = bytearray()
+    for i in range(int(sample_rate * duration)):
+        value = int(2000 * math.sin(2 * math.pi * 440 * i / sample_rate))
+        samples += value.to_bytes(2, 'little', signed=True)
+
+    if audio_format == 'wav':
+        buffer = io.BytesIO()
+        with wave.open(buffer, 'wb') as wav:
+            wav.setnchannels(1)
+            wav.setsampwidth(2)
+            wav.setframerate(sample_rate)
+            wav.writeframes(bytes(samples))
+        data = buffer.getvalue()
+    else:
+        data = bytes(samples)
+
+    return {
+        "id": f"audio_{random.randint(100000, 999999)}",
+        "data": base64.b64encode(data).decode('ascii'),
+        "expires_at": int(time.time()) + 3600,
+        "transcript": transcript
+    }
+
+def truncate_to_tokens(text: str, max_tokens: int) -> tuple[str, bool]:
+    """Cut text after max_tokens whitespace-separated tokens, keeping its formatting."""
+    tokens = list(re.finditer(r'\S+', text))
+    if len(tokens) <= max_tokens:
+        return text, False
+    if max_tokens <= 0:
+        return '', True
+    return text[:tokens[max_tokens - 1].end()], True
+
+def is_token_ids(value: Any) -> bool:
+    """Whether value is a non-empty array of integer token ids."""
+    return isinstance(value, list) and bool(value) and \
+        all(isinstance(item, int) and not isinstance(item, bool) for item in value)
+
+def token_id(word: str) -> int:
+    """The mock vocabulary id of a default-tokenizer token."""
+    return zlib.crc32(word.strip().encode('utf-8')) % MOCK_VOCAB_SIZE
+
+def decode_token_ids(ids: List[int], tokenizer: Optional[str] = None) -> str:
+    """Turn a token-id prompt into text, with tiktoken or as placeholder words."""
+    if tokenizer in TIKTOKEN_ENCODINGS:
+        return tiktoken.get_encoding(TIKTOKEN_ENCODINGS[tokenizer]).decode(ids)
+    return ' '.join(f"token{i}" for i in ids)
+
+def apply_stop_token_ids(text: str, stop_ids: List[int], tokenizer: Optional[str] = None) -> tuple[str, bool]:
+    """Cut text before the first generated token whose id is a stop id."""
+    if tokenizer in TIKTOKEN_ENCODINGS:
+        encoding = tiktoken.get_encoding(TIKTOKEN_ENCODINGS[tokenizer])
+        ids = encoding.encode(text)
+        cut = next((i for i, tid in enumerate(ids) if tid in stop_ids), None)
+        return (text, False) if cut is None else (encoding.decode(ids[:cut]), True)
+    for match in re.finditer(r'\s*\S+', text):
+        if token_id(match.group()) in stop_ids:
+            return text[:match.start()], True
+    return text, False
+
+def apply_stop_sequences(text: str, stop: Optional[List[Any]]) -> str:
+    """Cut text before the earliest stop sequence, which is itself never returned."""
+    cuts = [text.find(seq) for seq in stop or [] if isinstance(seq, str) and seq]
+    cuts = [cut for cut in cuts if cut >= 0]
+    return text[:min(cuts)] if cuts else text
+
+def resolve_language() -> tuple[str, Optional[tuple]]:
+    """Read the `x-mock-lang` header, returning (language, error)."""
+    language = request.headers.get('x-mock-lang', config.RESPONSE_LANGUAGE)
+    if language != 'en' and language not in LANGUAGE_WORDS:
+        return 'en', (f"Unsupported language '{language}'. Expected one of: en, "
+                      f"{', '.join(LANGUAGE_WORDS)}.", "x-mock-lang", "invalid_value")
+    return language, None
+
+def create_language_text(language: str) -> str:
+    """Build a few sentences from a language's bundled word list."""
+    sentences = []
+    for _ in range(random.randint(2, 3)):
+        words = random.choices(LANGUAGE_WORDS[language], k=random.randint(4, 8))
+        if language == 'ja':
+            # Japanese is written without spaces between words
+            sentences.append('、'.join([''.join(words[:2]), ''.join(words[2:])]) + '。')
+        else:
+            sentence = ' '.join(words)
+            sentences.append(sentence[0].upper() + sentence[1:] + '.')
+    return ('' if language == 'ja' else ' ').join(sentences)
+
+def fill_template(template: str, variables: Dict[str, str]) -> str:
+    """Replace `{{name}}` placeholders; unknown names are left as they are."""
+    return re.sub(r'\{\{\s*(\w+)\s*\}\}',
+                  lambda match: variables.get(match.group(1), match.group(0)), template)
+
+def create_logprobs(text: str, top_logprobs: int = 0) -> Dict[str, Any]:
+    """Create dummy chat `logprobs` for each word-sized token of text."""
+    tokens = re.findall(r'\s*\S+', text)
+    words = list(dict.fromkeys(text.split()))
+    spare_words = [word for word in dict.fromkeys(' '.join(DUMMY_CHAT_RESPONSES).split()) if word not in words]
+    content = []
+    for token in tokens:
+        # Mostly confident tokens, like a real model: many logprobs are tiny
+        logprob = -math.exp(random.uniform(-14, 1))
+        alternatives = [(token, logprob)]
+        # Other words of the text first, then made-up ones, so alternatives never repeat
+        others = [word for word in words if word != token.strip()]
+        others = random.sample(others, len(others)) + random.sample(spare_words, len(spare_words))
+        others += [f"{token.strip()}{i}" for i in range(2, top_logprobs - len(others) + 1)]
+        spacing = token[:len(token) - len(token.lstrip())]
+        for word in others[:max(top_logprobs - 1, 0)]:
+            alternatives.append((spacing + word, alternatives[-1][1] - random.uniform(0.5, 4)))
+        content.append({
+            "token": token,
+            "logprob": logprob,
+            "bytes": list(token.encode('utf-8')),
+            "top_logprobs": [
+                {"token": alt, "logprob": alt_logprob, "bytes": list(alt.encode('utf-8'))}
+                for alt, alt_logprob in alternatives[:top_logprobs]
+            ]
+        })
+    return {"content": content, "refusal": None}
+
+def create_legacy_logprobs(text: str, logprobs: int, offset: int = 0) -> Dict[str, Any]:
+    """Create dummy legacy completions `logprobs`: parallel arrays over text's tokens.
+
+    text_offset counts from the start of the prompt, so pass the prompt's
+    length as `offset` when text doesn't echo it.
+    """
+    content = create_logprobs(text, logprobs)["content"]
+    offsets = []
+    for entry in content:
+        offsets.append(offset)
+        offset += len(entry["token"])
+    return {
+        "tokens": [entry["token"] for entry in content],
+        "token_logprobs": [entry["logprob"] for entry in content],
+        "top_logprobs": [{alt["token"]: alt["logprob"] for alt in entry["top_logprobs"]}
+                         for entry in content],
+        "text_offset": offsets
+    }
+
+def create_content_parts(text: str) -> List[Dict[str, Any]]:
+    """Split text into one text part per paragraph, citing a source in the last one."""
+    parts = [{"type": "text", "text": paragraph, "annotations": []}
+             for paragraph in text.split('\n\n') if paragraph.strip()]
+    if parts:
+        last = parts[-1]["text"]
+        last_word = list(re.finditer(r'\S+', last))[-1]
+        parts[-1]["annotations"].append({
+            "type": "url_citation",
+            "url_citation": {
+                "start_index": last_word.start(),
+                "end_index": last_word.end(),
+                "url": "https://example.com/source",
+                "title": "Example source"
+            }
+        })
+    return parts
+
 def create_chat_completion(messages: List[Dict], model: str, **kwargs) -> Dict[str, Any]:
     """Create a dummy chat completion response."""
+    tool = choose_tool(kwargs.get('tools') or [], kwargs.get('tool_choice'))
+    answering_tool_result = bool(messages) and messages[-1].get('role') == 'tool'
+    if config.REASONING_SEGMENTS and tool is None and kwargs.get('tool_choice') != 'none' \
+            and not answering_tool_result:
+        # Agent mode: reason, then call a tool; answer once its result is back
+        tool = choose_tool(kwargs.get('tools') or [], 'required')
+    audio = kwargs.get('audio')
+    max_tokens = kwargs.get('max_tokens')
+    tokenizer = kwargs.get('tokenizer')
+
     # Extract the last user message
     user_message = ""
     for msg in reversed(messages):
         if msg.get('role') == 'user':
-            user_message = msg.get('content', '')
+            user_message = message_text(msg)
             break
 
     # Choose a response based on the message content
@@ -110,6 +1191,145 @@ def create_chat_completion(messages: List[Dict], model: str, **kwargs) -> Dict[s
     elif any(word in user_message.lower() for word in ['summarize', 'summary']):
         response_text = "Based on the text provided, here's a summary of the key points:\n\n- Main topic: The content discusses important concepts\n- Key findings: Multiple insights were presented\n- Conclusion: The information suggests several implications\n\nWould you like me to elaborate on any of these points?"
 
+    language = kwargs.get('language', 'en')
+    if language != 'en':
+        response_text = create_language_text(language)
+
+    if config.RESPONSE_TEMPLATE:
+        response_text = fill_template(config.RESPONSE_TEMPLATE, {
+            "user_name": kwargs.get('user') or "user",
+            "last_message": user_message,
+            "model": model,
+            "message_count": str(len(messages))
+        })
+
+    # Predicted outputs: the accepted part of the prediction leads the answer
+    predicted = kwargs.get('prediction') or ''
+    accepted = accepted_prediction(predicted)
+    if accepted and accepted != predicted:
+        response_text = f"{accepted} {response_text}"
+    elif accepted:
+        response_text = accepted
+
+    finish_reason = "stop"
+    stop = kwargs.get('stop')
+    response_text = apply_stop_sequences(response_text, [stop] if isinstance(stop, str) else stop)
+    if max_tokens is not None:
+        response_text, truncated = truncate_to_tokens(response_text, max_tokens)
+        if truncated:
+            finish_reason = "length"
+
+    message = {
+        "role": "assistant",
+        "content": response_text
+    }
+    completion_tokens = count_tokens(response_text, tokenizer)
+
+    if tool is not None:
+        arguments = json.dumps(create_dummy_arguments(tool.get('parameters') or {}))
+        message = {
+            "role": "assistant",
+            "content": None,
+            "tool_calls": [
+                {
+                    "id": f"call_{random.randint(100000, 999999)}",
+                    "type": "function",
+                    "function": {
+                        "name": tool.get('name', ''),
+                        "arguments": arguments
+                    }
+                }
+            ]
+        }
+        finish_reason = "tool_calls"
+        completion_tokens = count_tokens(arguments, tokenizer)
+
+        if config.LEGACY_FUNCTION_CALL:
+            # The deprecated single-function shape
+            message = {
+                "role": "assistant",
+                "content": None,
+                "function_call": message["tool_calls"][0]["function"]
+            }
+            finish_reason = "function_call"
+    elif audio is not None:
+        # Spoken answers carry the text as the audio transcript
+        message = {
+            "role": "assistant",
+            "content": None,
+            "audio": create_dummy_audio(response_text, audio.get('format', 'wav'))
+        }
+
+    last_user = next((msg for msg in reversed(messages) if msg.get('role') == 'user'), {})
+    if isinstance(message.get('content'), str) and (
+            config.MULTIPART_CONTENT == 'always' or
+            (config.MULTIPART_CONTENT == 'mirror' and isinstance(last_user.get('content'), list))):
+        message = {**message, "content": create_content_parts(message['content'])}
+
+    reasoning_tokens = 0
+    if config.REASONING_SEGMENTS:
+        if tool is not None:
+            reasoning = f"The user needs information I don't have, so I'll call `{tool.get('name', '')}` first."
+        elif answering_tool_result:
+            reasoning = "The tool returned its result, so I can answer the user now."
+        else:
+            reasoning = "I can answer this directly without calling a tool."
+        message = {**message, "reasoning_content": reasoning}
+        reasoning_tokens = count_tokens(reasoning, tokenizer)
+        completion_tokens += reasoning_tokens
+
+    logprobs = None
+    if kwargs.get('logprobs') and isinstance(message.get('content'), str):
+        logprobs = create_logprobs(message['content'], kwargs.get('top_logprobs') or 0)
+
+    prompt_tokens = sum(count_tokens(message_text(msg), tokenizer) for msg in messages)
+    usage = {
+        "prompt_tokens": prompt_tokens,
+        "completion_tokens": completion_tokens,
+        "total_tokens": prompt_tokens + completion_tokens
+    }
+    if predicted:
+        # Rejected prediction tokens are billed as completion tokens too
+        accepted_tokens = count_tokens(accepted, tokenizer)
+        rejected_tokens = count_tokens(predicted, tokenizer) - accepted_tokens
+        usage["completion_tokens"] += rejected_tokens
+        usage["total_tokens"] += rejected_tokens
+        usage["completion_tokens_details"] = {"accepted_prediction_tokens": accepted_tokens,
+                                              "rejected_prediction_tokens": rejected_tokens}
+    if config.REASONING_SEGMENTS:
+        usage.setdefault("completion_tokens_details", {})["reasoning_tokens"] = reasoning_tokens
+
+    return {
+        "id": f"chatcmpl-{random.randint(100000, 999999)}",
+        "object": "chat.completion",
+        "created": int(time.time()),
+        "model": model,
+        "choices": [
+            {
+                "index": 0,
+                "message": message,
+                "logprobs": logprobs,
+                "finish_reason": finish_reason
+            }
+        ],
+        "usage": usage
+    }
+
+def accepted_prediction(predicted: str) -> str:
+    """Return the leading PREDICTION_ACCEPT_RATE share of a prediction's words."""
+    words = predicted.split()
+    return ' '.join(words[:round(len(words) * config.PREDICTION_ACCEPT_RATE)])
+
+def is_ping(messages: List[Dict]) -> bool:
+    """Whether the last user message is the configured liveness marker."""
+    if not config.PING_MARKER:
+        return False
+    user_messages = [msg for msg in messages if msg.get('role') == 'user']
+    return bool(user_messages) and message_text(user_messages[-1]).strip() == config.PING_MARKER
+
+def create_pong_completion(messages: List[Dict], model: str) -> Dict[str, Any]:
+    """Create the fixed response to a liveness probe."""
+    prompt_tokens = sum(len(message_text(msg).split()) for msg in messages)
     return {
         "id": f"chatcmpl-{random.randint(100000, 999999)}",
         "object": "chat.completion",
@@ -118,20 +1338,389 @@ def create_chat_completion(messages: List[Dict], model: str, **kwargs) -> Dict[s
         "choices": [
             {
                 "index": 0,
-                "message": {
-                    "role": "assistant",
-                    "content": response_text
-                },
+                "message": {"role": "assistant", "content": config.PONG_RESPONSE},
                 "finish_reason": "stop"
             }
         ],
         "usage": {
-            "prompt_tokens": sum(len(msg.get('content', '').split()) for msg in messages),
-            "completion_tokens": len(response_text.split()),
-            "total_tokens": sum(len(msg.get('content', '').split()) for msg in messages) + len(response_text.split())
+            "prompt_tokens": prompt_tokens,
+            "completion_tokens": 1,
+            "total_tokens": prompt_tokens + 1
         }
     }
 
+def pong_stream(response: Dict[str, Any]):
+    """Stream a liveness probe response as a single content chunk."""
+    for delta, finish_reason in [({"role": "assistant", "content": config.PONG_RESPONSE}, None),
+                                 ({}, "stop")]:
+        yield format_sse_event(json.dumps({
+            "id": response["id"],
+            "object": "chat.completion.chunk",
+            "created": response["created"],
+            "model": response["model"],
+            "choices": [{"index": 0, "delta": delta, "finish_reason": finish_reason}]
+        }))
+    yield "data: [DONE]\n\n"
+
+def simulate_processing_delay() -> None:
+    """Sleep for the configured time it takes to generate one completion."""
+    time.sleep(random.uniform(
+        config.TIMING_SETTINGS["chat_completion_min_delay"],
+        config.TIMING_SETTINGS["chat_completion_max_delay"]
+    ))
+
+def create_chat_completions(n: int, messages: List[Dict], model: str, **kwargs) -> Dict[str, Any]:
+    """Generate n candidate completions concurrently and merge them into one response."""
+    def generate_candidate(_):
+        simulate_processing_delay()
+        return create_chat_completion(messages, model, **kwargs)
+
+    if n == 1:
+        return generate_candidate(0)
+
+    with ThreadPoolExecutor(max_workers=min(n, config.MAX_PARALLEL_CANDIDATES)) as pool:
+        candidates = list(pool.map(config.bind(generate_candidate), range(n)))
+    return merge_candidates(candidates)
+
+def merge_candidates(candidates: List[Dict[str, Any]]) -> Dict[str, Any]:
+    """Merge single-choice completions into one response with a choice per candidate."""
+    if len(candidates) == 1:
+        return candidates[0]
+
+    response = candidates[0]
+    response["choices"] = [
+        {**candidate["choices"][0], "index": index}
+        for index, candidate in enumerate(candidates)
+    ]
+    prompt_tokens = response["usage"]["prompt_tokens"]
+    completion_tokens = sum(candidate["usage"]["completion_tokens"] for candidate in candidates)
+    response["usage"] = {
+        "prompt_tokens": prompt_tokens,
+        "completion_tokens": completion_tokens,
+        "total_tokens": prompt_tokens + completion_tokens
+    }
+    if "completion_tokens_details" in candidates[0]["usage"]:
+        response["usage"]["completion_tokens_details"] = {key: sum(
+            candidate["usage"]["completion_tokens_details"][key] for candidate in candidates)
+            for key in candidates[0]["usage"]["completion_tokens_details"]}
+    return response
+
+def effective_params(data: Dict[str, Any], model: str, max_tokens: int, n: int) -> Dict[str, Any]:
+    """The sampling parameters a chat request runs with, defaulted and clamped to range."""
+    params = {}
+    for name, expected, minimum, maximum in CHAT_PARAM_RULES:
+        if expected != "decimal":
+            continue
+        value = data.get(name)
+        if json_type_name(value) not in ("integer", "decimal"):
+            value = get_default_temperature(model) if name == "temperature" else SAMPLING_DEFAULTS[name]
+        params[name] = min(max(value, minimum), maximum)
+    params.update(n=n, max_tokens=max_tokens)
+    return params
+
+def resolve_service_tier(requested: Any) -> tuple[Any, Optional[str]]:
+    """Pick the tier a request is served on, returning (tier, downgrade explanation).
+
+    Requests are downgraded one step (priority → default → flex) while at
+    least SERVICE_TIER_DOWNGRADE_STREAMS streams are open, or at random with
+    SERVICE_TIER_DOWNGRADE_RATE.
+    """
+    served = 'default' if requested in (None, 'auto') else requested
+    with ACTIVE_STREAMS_LOCK:
+        active = ACTIVE_STREAMS
+    reason = None
+    if config.SERVICE_TIER_DOWNGRADE_STREAMS and active >= config.SERVICE_TIER_DOWNGRADE_STREAMS:
+        reason = f"load ({active} active streams)"
+    elif random.random() < config.SERVICE_TIER_DOWNGRADE_RATE:
+        reason = "random"
+
+    if reason is None or served not in SERVICE_TIER_DOWNGRADES:
+        return served, None
+    return SERVICE_TIER_DOWNGRADES[served], \
+        f"requested={requested or 'auto'}; served={SERVICE_TIER_DOWNGRADES[served]}; reason={reason}"
+
+def enforce_min_latency(started_at: float) -> None:
+    """Sleep until at least MIN_LATENCY_MS has passed since started_at."""
+    remaining = config.MIN_LATENCY_MS / 1000 - (time.monotonic() - started_at)
+    if remaining > 0:
+        time.sleep(remaining)
+
+def stream_chunk_delay(tps: Optional[float] = None) -> float:
+    """Delay between streamed chunks, slowed down by concurrent streams.
+
+    A `model@tps=N` request streams at N chunks per second instead of the
+    configured streaming_chunk_delay.
+    """
+    with ACTIVE_STREAMS_LOCK:
+        active = ACTIVE_STREAMS
+    delay = 1 / tps if tps else config.TIMING_SETTINGS["streaming_chunk_delay"]
+    return delay * max(1, active ** config.THROUGHPUT_ALPHA)
+
+def parse_model_speed(model: str) -> tuple[str, Optional[float], Optional[tuple]]:
+    """Split a `model@tps=N` speed suffix off, returning (model, tps, error)."""
+    name, separator, suffix = model.rpartition('@')
+    if not separator or not suffix.startswith('tps='):
+        return model, None, None
+    try:
+        tps = float(suffix[len('tps='):])
+    except ValueError:
+        tps = 0.0
+    # Below the minimum a single chunk's delay would overflow time.sleep()
+    if not MIN_MODEL_TPS <= tps <= MAX_MODEL_TPS or not name:
+        return model, None, (f"Invalid speed suffix in model '{model}': expected '<model>@tps=<N>' "
+                             f"with N between {MIN_MODEL_TPS} and {MAX_MODEL_TPS}.", "model", "invalid_value")
+    return name, tps, None
+
+def track_active_stream(chunks):
+    """Count a stream as active while its chunks are being generated."""
+    global ACTIVE_STREAMS
+    with ACTIVE_STREAMS_LOCK:
+        ACTIVE_STREAMS += 1
+    try:
+        yield from chunks
+    finally:
+        with ACTIVE_STREAMS_LOCK:
+            ACTIVE_STREAMS -= 1
+
+def watch_backpressure(events, session_id: str):
+    """Log and count events the client took over BACKPRESSURE_LOG_MS to accept.
+
+    Yielding an event hands it to the server, which only asks for the next one
+    once the previous write finished, so the time spent suspended at a yield is
+    time a slow consumer kept the stream blocked.
+    """
+    global BACKPRESSURE_TOTAL
+    for event in events:
+        handed_off = time.monotonic()
+        yield event
+        blocked_ms = (time.monotonic() - handed_off) * 1000
+        if blocked_ms > config.BACKPRESSURE_LOG_MS:
+            with BACKPRESSURE_LOCK:
+                BACKPRESSURE_TOTAL += 1
+            logger.warning("Stream %s blocked %.0fms on a slow consumer", session_id, blocked_ms)
+
+def limit_event_rate(events):
+    """Hold each event until the global SSE event rate allows it."""
+    for event in events:
+        SSE_EVENT_LIMITER.wait()
+        yield event
+
+def request_etag(data: Dict[str, Any]) -> str:
+    """Derive an ETag from the request body that produced a completion."""
+    canonical = json.dumps(data, sort_keys=True, separators=(',', ':'))
+    return hashlib.sha256(canonical.encode()).hexdigest()[:32]
+
+def load_transcript(path: str) -> List[tuple[float, str]]:
+    """Read a recorded `.sse` transcript into (seconds since start, event) pairs.
+
+    Each event may be preceded by a `: t=<seconds>` comment line giving when it
+    was received; events without one keep the previous event's time.
+    """
+    with open(path, encoding='utf-8') as f:
+        blocks = [block for block in re.split(r'\r?\n\r?\n', f.read()) if block.strip()]
+
+    events, at = [], 0.0
+    for block in blocks:
+        lines = block.splitlines()
+        timing = re.fullmatch(r':\s*t=([0-9.]+)\s*', lines[0])
+        if timing:
+            at = float(timing.group(1))
+            lines = lines[1:]
+        if lines:
+            events.append((at, '\n'.join(lines) + '\n\n'))
+    return events
+
+def find_transcript(data: Dict[str, Any]) -> tuple[Optional[List[tuple[float, str]]], Optional[tuple]]:
+    """Find the transcript for a streaming request, returning (events, error).
+
+    The `x-mock-transcript` header names a file in SSE_TRANSCRIPT_DIR; without it
+    a file named after the request hash (its ETag) is used if one exists.
+    """
+    if not config.SSE_TRANSCRIPT_DIR:
+        return None, None
+
+    name = request.headers.get('x-mock-transcript')
+    if name is None:
+        path = os.path.join(config.SSE_TRANSCRIPT_DIR, f"{request_etag(data)}.sse")
+        return (load_transcript(path) if os.path.isfile(path) else None), None
+
+    path = os.path.join(config.SSE_TRANSCRIPT_DIR, f"{name}.sse")
+    if name != os.path.basename(name) or not os.path.isfile(path):
+        return None, (f"No transcript named '{name}' in {config.SSE_TRANSCRIPT_DIR}.",
+                      "x-mock-transcript", "transcript_not_found")
+    return load_transcript(path), None
+
+def replay_transcript(events: List[tuple[float, str]]):
+    """Replay recorded events with their original spacing, scaled by SSE_REPLAY_SCALE."""
+    started = time.monotonic()
+    for at, event in events:
+        delay = started + at * config.SSE_REPLAY_SCALE - time.monotonic()
+        if delay > 0:
+            time.sleep(delay)
+        yield event
+
+def store_completion(response: Dict[str, Any], etag: str) -> None:
+    """Keep a completion for later retrieval by id."""
+    with STORED_COMPLETIONS_LOCK:
+        STORED_COMPLETIONS[response["id"]] = (response, etag)
+        while len(STORED_COMPLETIONS) > config.STORED_COMPLETION_LIMIT:
+            STORED_COMPLETIONS.popitem(last=False)
+
+def shutdown_grace_expired() -> bool:
+    """Whether a shutdown is in progress and its grace period has run out."""
+    return SHUTDOWN_DEADLINE is not None and time.monotonic() >= SHUTDOWN_DEADLINE
+
+def remember_stream_session(response: Dict[str, Any]) -> None:
+    """Keep a streamed completion so a reconnecting client can resume it."""
+    with STREAM_SESSIONS_LOCK:
+        STREAM_SESSIONS[response["id"]] = response
+        while len(STREAM_SESSIONS) > config.STREAM_SESSION_LIMIT:
+            STREAM_SESSIONS.popitem(last=False)
+
+def find_stream_resume_point(last_event_id: str) -> tuple[Optional[Dict[str, Any]], int]:
+    """Return the stored completion and the next event index for a Last-Event-ID."""
+    completion_id, _, index = last_event_id.rpartition(':')
+    if not completion_id or not index.isdigit():
+        return None, 0
+
+    with STREAM_SESSIONS_LOCK:
+        response = STREAM_SESSIONS.get(completion_id)
+    if response is None:
+        return None, 0
+
+    return response, int(index) + 1
+
+def stream_deltas(message: Dict[str, Any]) -> List[Dict[str, Any]]:
+    """Split an assistant message into deltas, the first of which carries the role.
+
+    An empty message still streams a single role delta with `content: ""`.
+    """
+    deltas = message_deltas(message) or [{"content": ""}]
+    if message.get('reasoning_content'):
+        # The reasoning segment streams before the answer or tool call
+        deltas = [{"reasoning_content": piece}
+                  for piece in re.findall(r'\s*\S+', message['reasoning_content'])] + deltas
+    deltas[0] = {"role": "assistant", **deltas[0]}
+    return deltas
+
+def message_deltas(message: Dict[str, Any]) -> List[Dict[str, Any]]:
+    """Split an assistant message into the deltas streamed for it."""
+    if message.get('tool_calls'):
+        deltas = []
+        for index, call in enumerate(message['tool_calls']):
+            deltas.append({"tool_calls": [{
+                "index": index,
+                "id": call["id"],
+                "type": "function",
+                "function": {"name": call["function"]["name"], "arguments": ""}
+            }]})
+            arguments = call["function"]["arguments"]
+            for i in range(0, len(arguments), 8):
+                deltas.append({"tool_calls": [{
+                    "index": index,
+                    "function": {"arguments": arguments[i:i + 8]}
+                }]})
+        return deltas
+
+    if message.get('function_call'):
+        function_call = message['function_call']
+        arguments = function_call["arguments"]
+        deltas = [{"function_call": {"name": function_call["name"], "arguments": ""}}]
+        deltas += [{"function_call": {"arguments": arguments[i:i + 8]}}
+                   for i in range(0, len(arguments), 8)]
+        return deltas
+
+    if message.get('audio'):
+        audio = message['audio']
+        deltas = [{"audio": {"id": audio["id"], "transcript": ""}}]
+        deltas += [{"audio": {"transcript": char}} for char in audio["transcript"]]
+        deltas += [{"audio": {"data": audio["data"][i:i + 4096]}}
+                   for i in range(0, len(audio["data"]), 4096)]
+        deltas.append({"audio": {"expires_at": audio["expires_at"]}})
+        return deltas
+
+    if isinstance(message.get('content'), list):
+        # Structured content: each part streams its text, then its annotations
+        deltas = []
+        for index, part in enumerate(message['content']):
+            deltas += [{"content": [{"index": index, "type": "text", "text": char}]} for char in part["text"]]
+            if part.get("annotations"):
+                deltas.append({"content": [{"index": index, "type": "text", "text": "",
+                                            "annotations": part["annotations"]}]})
+        return deltas
+
+    # Stream the content character by character
+    return [{"content": char} for char in message.get('content') or '']
+
+def stream_events(choices: List[Dict[str, Any]],
+                  shuffle: Optional[random.Random] = None) -> List[tuple[int, Dict[str, Any], Optional[str]]]:
+    """List every (choice index, delta, finish_reason) event of a stream, in order.
+
+    Choices' deltas are interleaved round-robin, or in shuffle's random order
+    (each choice's own deltas stay in order), then each choice gets its finish
+    chunk.
+    """
+    per_choice = [stream_deltas(choice["message"]) for choice in choices]
+    events = []
+    if shuffle is not None:
+        pending = [(choice["index"], list(reversed(deltas))) for choice, deltas in zip(choices, per_choice)]
+        while pending:
+            index, deltas = shuffle.choice(pending)
+            events.append((index, deltas.pop(), None))
+            pending = [entry for entry in pending if entry[1]]
+    else:
+        for step in range(max(len(deltas) for deltas in per_choice)):
+            for choice, deltas in zip(choices, per_choice):
+                if step < len(deltas):
+                    events.append((choice["index"], deltas[step], None))
+    events += [(choice["index"], {}, choice["finish_reason"]) for choice in choices]
+    return events
+
+def delta_text(delta: Dict[str, Any]) -> str:
+    """Return the content text a delta adds, whether a string or content parts."""
+    content = delta.get('content')
+    if isinstance(content, list):
+        return ''.join(part.get('text', '') for part in content)
+    return content or ''
+
+def thinking_placeholder_deltas():
+    """Yield placeholder deltas for THINKING_PLACEHOLDER_SECS, pacing them out.
+
+    Each is either an empty delta or a one-dot `reasoning_content` delta,
+    depending on THINKING_PLACEHOLDER_STYLE.
+    """
+    deadline = time.monotonic() + config.THINKING_PLACEHOLDER_SECS
+    interval = config.THINKING_PLACEHOLDER_INTERVAL_MS / 1000
+    while time.monotonic() < deadline:
+        if config.THINKING_PLACEHOLDER_STYLE == 'reasoning':
+            yield {"role": "assistant", "content": None, "reasoning_content": "."}
+        else:
+            yield {}
+        time.sleep(max(0.0, min(interval, deadline - time.monotonic())))
+
+def obfuscation_padding() -> str:
+    """Random-length padding like the `obfuscation` field OpenAI adds to chunks."""
+    alphabet = 'ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789'
+    return ''.join(random.choice(alphabet) for _ in range(random.randint(1, 16)))
+
+def sse_garbage(rng: random.Random) -> str:
+    """A malformed SSE line for `x-mock-sse-garbage`; spec-following parsers ignore each."""
+    return rng.choice([
+        # A line with no field prefix at all
+        "this line is not a server-sent event field\n\n",
+        # A field that isn't part of the SSE spec
+        f"garbage: {obfuscation_padding()}\n\n",
+        # An event missing its blank-line terminator
+        "retry-without-terminator\n",
+    ])
+
+def format_sse_event(data: str, event_id: Optional[str] = None) -> str:
+    """Format a single Server-Sent Event, optionally with an `id:` field."""
+    data = format_json_numbers(data)
+    if event_id is None:
+        return f"data: {data}\n\n"
+    return f"id: {event_id}\ndata: {data}\n\n"
+
 def create_embedding_response(text: str, model: str) -> Dict[str, Any]:
     """Create a dummy embedding response."""
     # Simulate embedding generation time
@@ -157,135 +1746,694 @@ def create_embedding_response(text: str, model: str) -> Dict[str, Any]:
         }
     }
 
-@app.route('/v1/models', methods=['GET'])
+@api.route('/v1/models', methods=['GET'])
 def list_models():
     """List all available models."""
     is_valid, error = check_api_key()
     if not is_valid:
         return jsonify({"error": {"message": error, "type": "unauthorized"}}), 401
 
+    denied = check_organization()
+    if denied:
+        return denied
+
+    injected = maybe_inject_error('models')
+    if injected:
+        return injected
+
     return jsonify({
         "object": "list",
         "data": AVAILABLE_MODELS
     })
 
-@app.route('/v1/models/<model_id>', methods=['GET'])
+@api.route('/v1/models/<model_id>', methods=['GET'])
 def get_model(model_id: str):
     """Get information about a specific model."""
     is_valid, error = check_api_key()
     if not is_valid:
         return jsonify({"error": {"message": error, "type": "unauthorized"}}), 401
 
+    denied = check_organization()
+    if denied:
+        return denied
+
+    injected = maybe_inject_error('models')
+    if injected:
+        return injected
+
     model = next((m for m in AVAILABLE_MODELS if m["id"] == model_id), None)
     if not model:
         return jsonify({"error": {"message": "Model not found", "type": "not_found"}}), 404
 
     return jsonify(model)
 
-@app.route('/v1/chat/completions', methods=['POST'])
+@api.route('/v1/chat/completions', methods=['POST'])
 def create_completion():
     """Create a chat completion."""
+    started_at = time.monotonic()
     is_valid, error = check_api_key()
     if not is_valid:
         return jsonify({"error": {"message": error, "type": "unauthorized"}}), 401
 
+    denied = check_organization()
+    if denied:
+        return denied
+
     try:
-        data = request.get_json()
+        data, error_response = parse_json_body()
+        if error_response:
+            return error_response
 
         if not data:
-            return jsonify({"error": {"message": "Request body is required", "type": "invalid_request"}}), 400
+            return invalid_request("Request body is required")
+
+        # Strip the speed suffix first so validation sees the real model ID
+        requested_model, tps = data.get('model'), None
+        if isinstance(requested_model, str):
+            data['model'], tps, error = parse_model_speed(requested_model)
+            if error:
+                return invalid_request(*error)
+
+        error = validate_chat_request(data)
+        if error:
+            return invalid_request(*error)
+
+        tokenizer, error = resolve_tokenizer()
+        if error:
+            return invalid_request(*error)
+
+        language, error = resolve_language()
+        if error:
+            return invalid_request(*error)
+
+        duplicate_rate, error = resolve_duplicate_rate()
+        if error:
+            return invalid_request(*error)
+        sse_garbage_enabled = request.headers.get('x-mock-sse-garbage', '').lower() == 'true'
+        shuffle_choices = request.headers.get('x-mock-shuffle-choices', '').lower() == 'true'
+
+        # HTTP/1.0 has no chunked transfer encoding to carry a stream
+        if data.get('stream') and request.environ.get('SERVER_PROTOCOL') == 'HTTP/1.0':
+            if config.HTTP10_STREAM == 'error':
+                return invalid_request("Streaming requires HTTP/1.1; HTTP/1.0 clients must set "
+                                       "'stream' to false.", "stream", "http_version_not_supported")
+            data['stream'] = False
+
+        if config.STRICT_STREAM_NEGOTIATION:
+            error = check_stream_negotiation(bool(data.get('stream')))
+            if error:
+                return invalid_request(*error)
 
         messages = data.get('messages', [])
         model = data.get('model', 'gpt-3.5-turbo')
-        max_tokens = data.get('max_tokens', 150)
-        temperature = data.get('temperature', 0.7)
+        echoed_model = requested_model if config.ECHO_MODEL_SUFFIX and requested_model else model
+        truncated = False
+        if config.AUTO_TRUNCATE:
+            messages, truncated = truncate_prompt(messages, tokenizer)
+
+        # Liveness probes skip fault injection and every simulated delay
+        if is_ping(messages):
+            response = create_pong_completion(messages, model)
+            if data.get('stream', False):
+                return Response(pong_stream(response), mimetype='text/plain')
+            return jsonify(response)
+
+        unavailable = check_model_available(model)
+        if unavailable:
+            return unavailable
+
+        exhausted = check_lifetime_budget()
+        if exhausted:
+            return exhausted
+
+        # Recorded transcripts replay exactly as they were captured
+        if data.get('stream', False):
+            transcript, error = find_transcript(data)
+            if error:
+                return invalid_request(*error)
+            if transcript:
+                return Response(track_active_stream(replay_transcript(transcript)), mimetype='text/plain')
+
+        injected = maybe_inject_error('chat')
+        if injected:
+            return injected
+
+        # The newer max_completion_tokens wins when both are sent
+        max_tokens = data.get('max_completion_tokens', data.get('max_tokens'))
+        if not isinstance(max_tokens, int) or isinstance(max_tokens, bool):
+            max_tokens = get_default_max_tokens(model)
         stream = data.get('stream', False)
+        tools = data.get('tools') or []
+        tool_choice = data.get('tool_choice')
+        if not tools and data.get('functions'):
+            # Legacy `functions`/`function_call` requests map onto tools
+            tools = [{"type": "function", "function": f} for f in data['functions']]
+            function_call = data.get('function_call')
+            tool_choice = {"type": "function", "function": function_call} \
+                if isinstance(function_call, dict) else function_call
+        audio = (data.get('audio') or {}) if 'audio' in (data.get('modalities') or []) else None
+        n = data.get('n')
+        n = min(n, 128) if isinstance(n, int) and not isinstance(n, bool) and n >= 1 else 1
+        params = effective_params(data, model, max_tokens, n)
 
-        if not messages:
-            return jsonify({"error": {"message": "messages is required", "type": "invalid_request"}}), 400
+        if config.RATE_LIMIT_ENABLED:
+            prompt_tokens = sum(count_tokens(message_text(msg), tokenizer) for msg in messages)
+            # A negative max_tokens (accepted without STRICT_VALIDATION) must not refill the bucket
+            limited = check_token_rate_limit(prompt_tokens + max(max_tokens, 1) * n, model)
+            if limited:
+                return limited
 
-        # Simulate processing time
-        processing_delay = random.uniform(0.5, 2.0)
-        time.sleep(processing_delay)
+        # Options shared by every generated candidate
+        options = {
+            "tools": tools,
+            "tool_choice": tool_choice,
+            "audio": audio,
+            "max_tokens": max_tokens,
+            "stop": data.get('stop'),
+            "prediction": message_text(data['prediction']) if data.get('prediction') else None,
+            "tokenizer": tokenizer,
+            "language": language,
+            "user": data.get('user') if isinstance(data.get('user'), str) else None,
+            "logprobs": data.get('logprobs') is True,
+            "top_logprobs": data.get('top_logprobs') if isinstance(data.get('top_logprobs'), int) else 0
+        }
+
+        service_tier, downgrade = resolve_service_tier(data.get('service_tier'))
 
         if stream:
-            # For streaming responses, we'll return Server-Sent Events
-            def generate():
-                response = create_chat_completion(messages, model)
-                content = response["choices"][0]["message"]["content"]
-
-                # Stream the response character by character
-                current_content = ""
-                for i, char in enumerate(content):
-                    current_content += char
-                    chunk = {
-                        "id": response["id"],
-                        "object": "chat.completion.chunk",
-                        "created": response["created"],
-                        "model": model,
-                        "choices": [
-                            {
-                                "index": 0,
-                                "delta": {"content": char},
-                                "finish_reason": None
-                            }
-                        ]
-                    }
-                    yield f"data: {json.dumps(chunk)}\n\n"
-                    time.sleep(0.01)  # Small delay to simulate streaming
+            # Simulate processing time
+            simulate_processing_delay()
+
+            # A reconnecting client resumes the completion it was reading
+            response, start = None, 0
+            last_event_id = request.headers.get('Last-Event-ID')
+            if config.STREAM_EVENT_IDS and last_event_id:
+                response, start = find_stream_resume_point(last_event_id)
+
+            if response is None:
+                response = merge_candidates([create_chat_completion(messages, model, **options)
+                                             for _ in range(n)])
+                response["service_tier"] = service_tier
+                response["model"] = echoed_model
+                if config.ECHO_PARAMS:
+                    response["x_effective_params"] = params
+                apply_fixed_id(response)
+                record_generated_tokens(response["usage"])
+                if config.STREAM_EVENT_IDS:
+                    remember_stream_session(response)
+                if data.get('store'):
+                    store_completion(response, request_etag(data))
+
+            def event_id(index: int) -> Optional[str]:
+                if not config.STREAM_EVENT_IDS:
+                    return None
+                return f"{response['id']}:{index}"
+
+            stream_options = data.get('stream_options')
+            include_usage = stream_options.get('include_usage') is True \
+                if isinstance(stream_options, dict) else config.STREAMING_SETTINGS["include_usage_in_stream"]
 
-                # Send final chunk
-                final_chunk = {
+            def chunk(delta: Optional[Dict[str, Any]], finish_reason: Optional[str] = None,
+                      index: int = 0) -> str:
+                payload = {
                     "id": response["id"],
                     "object": "chat.completion.chunk",
                     "created": response["created"],
-                    "model": model,
+                    "model": response["model"],
+                    "service_tier": response.get("service_tier"),
                     "choices": [
                         {
-                            "index": 0,
-                            "delta": {},
-                            "finish_reason": "stop"
+                            "index": index,
+                            "delta": delta,
+                            "finish_reason": finish_reason
                         }
-                    ]
+                    ] if delta is not None else []
                 }
-                yield f"data: {json.dumps(final_chunk)}\n\n"
+                if finish_reason is not None and "x_effective_params" in response:
+                    payload["x_effective_params"] = response["x_effective_params"]
+                if include_usage:
+                    # Only the terminal chunk, which has no choices, carries usage
+                    payload["usage"] = response["usage"] if delta is None else None
+                if config.STREAM_OBFUSCATION:
+                    payload["obfuscation"] = obfuscation_padding()
+                return json.dumps(payload)
+
+            # For streaming responses, we'll return Server-Sent Events
+            def generate():
+                global FORCED_STREAMS
+                # validate_chat_request() has checked that `seed` is an integer
+                seed = data.get('seed')
+                # Shuffled delivery follows the seed, or else the completion id, so a resumed
+                # stream sees the same order
+                shuffle_seed = seed if seed is not None else response["id"]
+                events = stream_events(response["choices"],
+                                       random.Random(shuffle_seed) if shuffle_choices else None)
+                # Duplicates and garbage lines follow the seed too, so a failure can be replayed
+                faults = random.Random(seed)
+                enforce_min_latency(started_at)
+
+                # "Thinking" placeholders only precede a fresh stream
+                if start == 0:
+                    for delta in thinking_placeholder_deltas():
+                        if shutdown_grace_expired():
+                            break
+                        yield format_sse_event(chunk(delta))
+
+                checksum = hashlib.sha256()
+                # Accepted prediction text needs no generating, so it streams without delay
+                accepted = len(accepted_prediction(options["prediction"] or ''))
+                streamed = {}
+                for i in range(start, len(events)):
+                    index, delta, finish_reason = events[i]
+                    if shutdown_grace_expired():
+                        # The server is going away: end the stream cleanly
+                        with ACTIVE_STREAMS_LOCK:
+                            FORCED_STREAMS += 1
+                        for choice in response["choices"]:
+                            yield format_sse_event(chunk({}, "length", choice["index"]))
+                        yield "data: [DONE]\n\n"
+                        return
+                    event = format_sse_event(chunk(delta, finish_reason, index), event_id(i))
+                    yield event
+                    if delta.get('content') and faults.random() < duplicate_rate:
+                        # At-least-once delivery: the same chunk again, not counted in usage
+                        yield event
+                    if sse_garbage_enabled and faults.random() < 0.3:
+                        yield sse_garbage(faults)
+                    checksum.update(delta_text(delta).encode('utf-8'))
+                    text = delta_text(delta)
+                    streamed[index] = streamed.get(index, 0) + len(text)
+                    if finish_reason is None and not (text and streamed[index] <= accepted):
+                        time.sleep(stream_chunk_delay(tps))
+
+                if include_usage and start <= len(events):
+                    yield format_sse_event(chunk(None), event_id(len(events)))
+                if config.CONTENT_CHECKSUM:
+                    # The dev server can't send HTTP trailers, so this goes in an SSE comment
+                    yield f": x-mock-content-sha256={checksum.hexdigest()}\n\n"
                 yield "data: [DONE]\n\n"
 
-            return Response(generate(), mimetype='text/plain')
+            result = Response(watch_backpressure(track_active_stream(limit_event_rate(generate())),
+                                                 response["id"]), mimetype='text/plain')
+            if truncated and config.TRUNCATION_HEADER:
+                result.headers['x-mock-truncated'] = 'true'
+            if downgrade:
+                result.headers['x-mock-service-tier-downgrade'] = downgrade
+            return result
 
         else:
-            response = create_chat_completion(messages, model)
+            response = create_chat_completions(n, messages, model, **options)
+            response["service_tier"] = service_tier
+            response["model"] = echoed_model
+            if config.ECHO_PARAMS:
+                response["x_effective_params"] = params
+            apply_fixed_id(response)
+            record_generated_tokens(response["usage"])
+            enforce_min_latency(started_at)
+            result = jsonify(response)
+            if data.get('store'):
+                etag = request_etag(data)
+                store_completion(response, etag)
+                result.set_etag(etag)
+            if truncated and config.TRUNCATION_HEADER:
+                result.headers['x-mock-truncated'] = 'true'
+            if downgrade:
+                result.headers['x-mock-service-tier-downgrade'] = downgrade
+            return result
+
+    except Exception as e:
+        return jsonify({"error": {"message": str(e), "type": "internal_server_error"}}), 500
+
+@api.route('/v1/chat/completions/<completion_id>', methods=['GET'])
+def get_stored_completion(completion_id: str):
+    """Retrieve a completion created with `store: true`."""
+    is_valid, error = check_api_key()
+    if not is_valid:
+        return jsonify({"error": {"message": error, "type": "unauthorized"}}), 401
+
+    denied = check_organization()
+    if denied:
+        return denied
+
+    injected = maybe_inject_error('chat')
+    if injected:
+        return injected
+
+    with STORED_COMPLETIONS_LOCK:
+        stored = STORED_COMPLETIONS.get(completion_id)
+    if not stored:
+        return jsonify({"error": {"message": "Completion not found", "type": "not_found"}}), 404
+
+    response, etag = stored
+    if request.if_none_match.contains(etag):
+        result = Response(status=304)
+    else:
+        result = jsonify(response)
+    result.set_etag(etag)
+    return result
+
+def completion_cost(model: str, usage: Dict[str, int]) -> Optional[float]:
+    """Price a completion's usage, in USD, from the model's configured rates."""
+    settings = get_model_settings(model)
+    if 'cost_per_1k_input_tokens' not in settings:
+        return None
+    return round(usage["prompt_tokens"] / 1000 * settings["cost_per_1k_input_tokens"]
+                 + usage["completion_tokens"] / 1000 * settings["cost_per_1k_output_tokens"], 8)
+
+@api.route('/v1/compare', methods=['POST'])
+def compare_models():
+    """Answer one conversation with several models at once (non-standard)."""
+    started_at = time.monotonic()
+    is_valid, error = check_api_key()
+    if not is_valid:
+        return jsonify({"error": {"message": error, "type": "unauthorized"}}), 401
+
+    denied = check_organization()
+    if denied:
+        return denied
+
+    try:
+        data, error_response = parse_json_body()
+        if error_response:
+            return error_response
+
+        if not data:
+            return invalid_request("Request body is required")
+
+        models = data.get('models')
+        if not isinstance(models, list) or not models:
+            return invalid_request("'models' must be a non-empty array of model IDs.", "models",
+                                   "missing_required_parameter" if models is None else "invalid_type")
+        if data.get('stream'):
+            return invalid_request("Streaming is not supported by /v1/compare.", "stream", "unsupported_value")
+
+        # Each model goes through the same speed suffix parsing and validation as a chat request
+        jobs = []
+        for requested_model in models:
+            model_request = {**{k: v for k, v in data.items() if k != 'models'}, "model": requested_model}
+            if isinstance(requested_model, str):
+                model_request["model"], _, error = parse_model_speed(requested_model)
+                if error:
+                    return invalid_request(error[0], "models", error[2])
+            error = validate_chat_request(model_request)
+            if error:
+                message, param, code = error
+                return invalid_request(message, "models" if param == "model" else param, code)
+            max_tokens = model_request.get('max_completion_tokens', model_request.get('max_tokens'))
+            if not isinstance(max_tokens, int) or isinstance(max_tokens, bool):
+                max_tokens = get_default_max_tokens(model_request["model"])
+            jobs.append((requested_model, model_request, max_tokens))
+
+        tokenizer, error = resolve_tokenizer()
+        if error:
+            return invalid_request(*error)
+
+        for _, model_request, _ in jobs:
+            unavailable = check_model_available(model_request["model"])
+            if unavailable:
+                return unavailable
+
+        exhausted = check_lifetime_budget()
+        if exhausted:
+            return exhausted
+
+        injected = maybe_inject_error('chat')
+        if injected:
+            return injected
+
+        # Each model's answer is its own upstream call, so each reserves its own tokens
+        if config.RATE_LIMIT_ENABLED:
+            prompt_tokens = sum(count_tokens(message_text(msg), tokenizer) for msg in data['messages'])
+            for _, model_request, max_tokens in jobs:
+                limited = check_token_rate_limit(prompt_tokens + max(max_tokens, 1), model_request["model"])
+                if limited:
+                    return limited
+
+        def answer(job: tuple) -> Dict[str, Any]:
+            requested_model, model_request, max_tokens = job
+            model = model_request["model"]
+            model_started = time.monotonic()
+            time.sleep(get_processing_delay(model))
+            completion = create_chat_completion(model_request["messages"], model, max_tokens=max_tokens,
+                                                stop=model_request.get('stop'), tokenizer=tokenizer)
+            if config.ECHO_MODEL_SUFFIX:
+                completion["model"] = requested_model
+            record_generated_tokens(completion["usage"])
+            return {
+                "model": requested_model,
+                "latency_ms": int((time.monotonic() - model_started) * 1000),
+                "cost": completion_cost(model, completion["usage"]),
+                "completion": completion
+            }
+
+        # Models answer concurrently, each with its own latency
+        with ThreadPoolExecutor(max_workers=min(len(models), config.MAX_PARALLEL_CANDIDATES)) as pool:
+            results = list(pool.map(config.bind(answer), jobs))
+        enforce_min_latency(started_at)
+
+        return jsonify({"object": "comparison", "data": results})
+
+    except Exception as e:
+        return jsonify({"error": {"message": str(e), "type": "internal_server_error"}}), 500
+
+def create_text_completion(prompts: List[str], model: str, **kwargs) -> Dict[str, Any]:
+    """Create a dummy legacy `text_completion` response with a choice per prompt."""
+    echo = kwargs.pop('echo', False)
+    logprobs = kwargs.pop('logprobs', None)
+    stop = kwargs.get('stop')
+    choices, prompt_tokens, completion_tokens = [], 0, 0
+    for index, prompt in enumerate(prompts):
+        completion = create_chat_completion([{"role": "user", "content": prompt}], model, **kwargs)
+        choice = completion["choices"][0]
+        generated, finish_reason = choice["message"]["content"] or '', choice["finish_reason"]
+        if is_token_ids(stop):
+            generated, stopped = apply_stop_token_ids(generated, stop, kwargs.get('tokenizer'))
+            if stopped:
+                finish_reason = "stop"
+                completion["usage"]["completion_tokens"] = count_tokens(generated, kwargs.get('tokenizer'))
+        text = (prompt if echo else '') + generated
+        choices.append({
+            "text": text,
+            "index": index,
+            "logprobs": create_legacy_logprobs(text, logprobs, 0 if echo else len(prompt))
+                        if logprobs is not None else None,
+            "finish_reason": finish_reason
+        })
+        prompt_tokens += completion["usage"]["prompt_tokens"]
+        completion_tokens += completion["usage"]["completion_tokens"]
+
+    return {
+        "id": f"cmpl-{random.randint(100000, 999999)}",
+        "object": "text_completion",
+        "created": int(time.time()),
+        "model": model,
+        "choices": choices,
+        "usage": {
+            "prompt_tokens": prompt_tokens,
+            "completion_tokens": completion_tokens,
+            "total_tokens": prompt_tokens + completion_tokens
+        }
+    }
+
+def complete_text(model: Optional[str] = None):
+    """Handle a legacy completions request, for `model` or the one in the body."""
+    started_at = time.monotonic()
+    is_valid, error = check_api_key()
+    if not is_valid:
+        return jsonify({"error": {"message": error, "type": "unauthorized"}}), 401
+
+    denied = check_organization()
+    if denied:
+        return denied
+
+    try:
+        data, error_response = parse_json_body()
+        if error_response:
+            return error_response
+
+        if not data:
+            return invalid_request("Request body is required")
+
+        requested_model = model or data.get('model')
+        if not isinstance(requested_model, str):
+            return invalid_request("you must provide a model parameter", "model", "missing_required_parameter")
+        model, tps, error = parse_model_speed(requested_model)
+        if error:
+            return invalid_request(*error)
+
+        # A prompt is a string, a token-id array, or an array of either
+        prompt = data.get('prompt', '<|endoftext|>')
+        if isinstance(prompt, str) or is_token_ids(prompt):
+            prompts = [prompt]
+        elif isinstance(prompt, list) and prompt and all(isinstance(p, str) or is_token_ids(p) for p in prompt):
+            prompts = prompt
+        else:
+            return invalid_request("prompt must be a string, an array of strings, or an array of token ids",
+                                   "prompt", "invalid_type")
+
+        stop = data.get('stop')
+        if stop is not None and not (isinstance(stop, str) or is_token_ids(stop) or (
+                isinstance(stop, list) and all(isinstance(seq, str) for seq in stop))):
+            return invalid_request(*invalid_type("stop", "string, array of strings or array of token ids", stop))
+        if isinstance(stop, list) and len(stop) > 4:
+            return invalid_request(f"Invalid 'stop': array too long. Expected an array with maximum length 4, "
+                                   f"but got an array with length {len(stop)} instead.",
+                                   "stop", "array_above_max_length")
+
+        # The legacy API caps logprobs at 5 alternatives
+        error = validate_param_range(data, "logprobs", "integer", 0, 5)
+        if error:
+            return invalid_request(*error)
+
+        tokenizer, error = resolve_tokenizer()
+        if error:
+            return invalid_request(*error)
+        prompts = [decode_token_ids(p, tokenizer) if is_token_ids(p) else p for p in prompts]
+
+        unavailable = check_model_available(model)
+        if unavailable:
+            return unavailable
+
+        exhausted = check_lifetime_budget()
+        if exhausted:
+            return exhausted
+
+        injected = maybe_inject_error('chat')
+        if injected:
+            return injected
+
+        # Legacy completions default to 16 tokens, as they did upstream
+        max_tokens = data.get('max_tokens')
+        if not isinstance(max_tokens, int) or isinstance(max_tokens, bool):
+            max_tokens = 16
+
+        simulate_processing_delay()
+        response = create_text_completion(prompts, model, max_tokens=max_tokens, stop=data.get('stop'),
+                                          tokenizer=tokenizer, echo=data.get('echo') is True,
+                                          logprobs=data.get('logprobs'))
+        if config.ECHO_MODEL_SUFFIX:
+            response["model"] = requested_model
+        apply_fixed_id(response)
+        record_generated_tokens(response["usage"])
+        enforce_min_latency(started_at)
+
+        if not data.get('stream', False):
             return jsonify(response)
 
+        def chunk(index: int, text: str, finish_reason: Optional[str] = None) -> str:
+            return json.dumps({
+                "id": response["id"],
+                "object": "text_completion",
+                "created": response["created"],
+                "model": response["model"],
+                "choices": [{"text": text, "index": index, "logprobs": None, "finish_reason": finish_reason}]
+            })
+
+        def generate():
+            for choice in response["choices"]:
+                for char in choice["text"]:
+                    yield format_sse_event(chunk(choice["index"], char))
+                    time.sleep(stream_chunk_delay(tps))
+                yield format_sse_event(chunk(choice["index"], '', choice["finish_reason"]))
+            yield "data: [DONE]\n\n"
+
+        return Response(track_active_stream(limit_event_rate(generate())), mimetype='text/plain')
+
     except Exception as e:
         return jsonify({"error": {"message": str(e), "type": "internal_server_error"}}), 500
 
-@app.route('/v1/embeddings', methods=['POST'])
+@api.route('/v1/completions', methods=['POST'])
+def create_legacy_completion():
+    """Create a legacy text completion."""
+    return complete_text()
+
+@api.route('/v1/engines/<engine>/completions', methods=['POST'])
+def create_engine_completion(engine: str):
+    """Create a text completion on the pre-models `/engines` path."""
+    if not config.LEGACY_ENGINES:
+        return not_found(None)
+    return complete_text(engine)
+
+def anthropic_error(status: int, error_type: str, message: str):
+    """An error in Anthropic's `{"type": "error", "error": {...}}` shape."""
+    return jsonify({"type": "error", "error": {"type": error_type, "message": message}}), status
+
+@api.route('/v1/messages/count_tokens', methods=['POST'])
+def count_message_tokens():
+    """Count a Messages API request's input tokens, like Anthropic's endpoint."""
+    # Anthropic clients send the key in x-api-key rather than as a bearer token
+    if request.headers.get('x-api-key', '') != REQUIRED_API_KEY and not check_api_key()[0]:
+        return anthropic_error(401, "authentication_error", "invalid x-api-key")
+
+    data, error_response = parse_json_body()
+    if error_response:
+        response, status = error_response
+        error = response.get_json()["error"]
+        return anthropic_error(status, "request_too_large" if status == 413 else error["type"], error["message"])
+    if not data:
+        return anthropic_error(400, "invalid_request_error", "Request body is required")
+    if not isinstance(data.get('model'), str):
+        return anthropic_error(400, "invalid_request_error", "model: Field required")
+    messages = data.get('messages')
+    if not isinstance(messages, list) or any(not isinstance(msg, dict) for msg in messages):
+        return anthropic_error(400, "invalid_request_error", "messages: Input should be a valid list")
+
+    tokenizer, error = resolve_tokenizer()
+    if error:
+        return anthropic_error(400, "invalid_request_error", error[0])
+
+    # `system` is a string or, like message content, a list of text blocks
+    system = data.get('system')
+    texts = [message_text({"content": system})] + [message_text(msg) for msg in messages]
+    return jsonify({"input_tokens": sum(count_tokens(text, tokenizer) for text in texts)})
+
+@api.route('/v1/embeddings', methods=['POST'])
 def create_embeddings():
     """Create embeddings for text."""
+    started_at = time.monotonic()
     is_valid, error = check_api_key()
     if not is_valid:
         return jsonify({"error": {"message": error, "type": "unauthorized"}}), 401
 
+    denied = check_organization()
+    if denied:
+        return denied
+
+    injected = maybe_inject_error('embeddings')
+    if injected:
+        return injected
+
     try:
-        data = request.get_json()
+        data, error_response = parse_json_body()
+        if error_response:
+            return error_response
 
         if not data:
-            return jsonify({"error": {"message": "Request body is required", "type": "invalid_request"}}), 400
+            return invalid_request("Request body is required")
 
         input_text = data.get('input', '')
         model = data.get('model', 'text-embedding-ada-002')
 
+        if not isinstance(model, str):
+            return invalid_request(*invalid_type("model", "string", model))
         if not input_text:
-            return jsonify({"error": {"message": "input is required", "type": "invalid_request"}}), 400
+            return invalid_request("Missing required parameter: 'input'.", "input", "missing_required_parameter")
 
         # Handle both single text and array of texts
         if isinstance(input_text, str):
             texts = [input_text]
-        elif isinstance(input_text, list):
+        elif isinstance(input_text, list) and all(isinstance(text, str) for text in input_text):
             texts = input_text
         else:
-            return jsonify({"error": {"message": "input must be string or array of strings", "type": "invalid_request"}}), 400
+            return invalid_request("input must be string or array of strings", "input", "invalid_type")
+
+        tokenizer, error = resolve_tokenizer()
+        if error:
+            return invalid_request(*error)
+
+        unavailable = check_model_available(model)
+        if unavailable:
+            return unavailable
 
         # Generate embeddings for all texts
         data_items = []
@@ -298,7 +2446,8 @@ def create_embeddings():
                 "index": i
             })
 
-        total_tokens = sum(len(text.split()) for text in texts)
+        total_tokens = sum(count_tokens(text, tokenizer) for text in texts)
+        enforce_min_latency(started_at)
 
         return jsonify({
             "object": "list",
@@ -313,7 +2462,7 @@ def create_embeddings():
     except Exception as e:
         return jsonify({"error": {"message": str(e), "type": "internal_server_error"}}), 500
 
-@app.route('/health', methods=['GET'])
+@api.route('/health', methods=['GET'])
 def health_check():
     """Health check endpoint."""
     return jsonify({
@@ -322,7 +2471,52 @@ def health_check():
         "version": "1.0.0"
     })
 
-@app.route('/', methods=['GET'])
+@api.route('/metrics', methods=['GET'])
+def metrics():
+    """Expose server counters in the Prometheus text format."""
+    if not config.ENABLE_METRICS:
+        return not_found(None)
+    with ACTIVE_STREAMS_LOCK:
+        active, forced = ACTIVE_STREAMS, FORCED_STREAMS
+    lines = [
+        "# HELP mock_active_streams Streams currently being sent.",
+        "# TYPE mock_active_streams gauge",
+        f"mock_active_streams {active}",
+        "# HELP mock_forced_stream_closes_total Streams cut short by shutdown.",
+        "# TYPE mock_forced_stream_closes_total counter",
+        f"mock_forced_stream_closes_total {forced}",
+        "# HELP mock_stream_backpressure_total Stream writes blocked on a slow consumer.",
+        "# TYPE mock_stream_backpressure_total counter",
+        f"mock_stream_backpressure_total {BACKPRESSURE_TOTAL}"
+    ]
+    return Response('\n'.join(lines) + '\n', mimetype='text/plain; version=0.0.4')
+
+@api.route('/stats', methods=['GET'])
+def stats():
+    """Report tokens generated so far and what's left of LIFETIME_TOKEN_BUDGET."""
+    with GENERATED_TOKENS_LOCK:
+        generated = GENERATED_TOKENS
+    budget = config.LIFETIME_TOKEN_BUDGET or None
+    return jsonify({
+        "object": "stats",
+        "generated_tokens": generated,
+        "lifetime_token_budget": budget,
+        "remaining_budget": max(0, budget - generated) if budget else None
+    })
+
+@api.route('/scenario/reset', methods=['POST'])
+def reset_scenario():
+    """Restart ERROR_SCHEDULE from its first entry."""
+    global ERROR_SCHEDULE_POSITION
+    with ERROR_SCHEDULE_LOCK:
+        ERROR_SCHEDULE_POSITION = 0
+    return jsonify({
+        "object": "scenario",
+        "error_schedule": [status or "ok" for status in config.ERROR_SCHEDULE],
+        "position": 0
+    })
+
+@api.route('/', methods=['GET'])
 def root():
     """Root endpoint with API information."""
     return jsonify({
@@ -331,21 +2525,279 @@ def root():
         "endpoints": {
             "models": "/v1/models",
             "chat_completions": "/v1/chat/completions",
+            "completions": "/v1/completions",
             "embeddings": "/v1/embeddings",
-            "health": "/health"
+            "compare": "/v1/compare",
+            "count_tokens": "/v1/messages/count_tokens",
+            "health": "/health",
+            "metrics": "/metrics",
+            "stats": "/stats",
+            "scenario_reset": "/scenario/reset"
         },
         "api_key": REQUIRED_API_KEY,
         "documentation": "https://platform.openai.com/docs/api-reference"
     })
 
-@app.errorhandler(404)
+class DecompressRequestMiddleware:
+    """WSGI middleware that transparently decompresses gzip/deflate request bodies.
+
+    The decompressed size is capped at MAX_REQUEST_BYTES, so a small
+    compressed body can't expand into an unbounded amount of memory.
+    """
+
+    # Content-Encoding -> zlib wbits (deflate is zlib-wrapped per RFC 9110)
+    ENCODINGS = {'gzip': 31, 'x-gzip': 31, 'deflate': 15}
+
+    def __init__(self, wsgi_app, settings: Optional[Dict[str, Any]] = None):
+        self.wsgi_app = wsgi_app
+        # Runs before the request binds its config, so it lays the app's settings over it itself
+        self.config = ConfigOverlay(config, settings or {})
+
+    def __call__(self, environ, start_response):
+        encoding = environ.get('HTTP_CONTENT_ENCODING', '').strip().lower()
+        if not encoding or encoding == 'identity':
+            return self.wsgi_app(environ, start_response)
+
+        if encoding not in self.ENCODINGS:
+            return self.error(415, f"Unsupported Content-Encoding: {encoding}",
+                              "unsupported_content_encoding")(environ, start_response)
+
+        limit = self.config.MAX_REQUEST_BYTES
+        # Never read past Content-Length: on a raw socket that would block
+        length = environ.get('CONTENT_LENGTH')
+        if length and length.isdigit():
+            compressed = environ['wsgi.input'].read(min(int(length), limit + 1))
+        else:
+            compressed = environ['wsgi.input'].read(limit + 1)
+        if len(compressed) > limit:
+            return self.error(413, f"Request body exceeds the maximum size of {limit} bytes",
+                              "request_too_large")(environ, start_response)
+
+        try:
+            decompressor = zlib.decompressobj(self.ENCODINGS[encoding])
+            body = decompressor.decompress(compressed, limit + 1)
+        except zlib.error:
+            return self.error(400, f"Request body is not valid {encoding} data",
+                              "invalid_content_encoding")(environ, start_response)
+        if len(body) > limit or decompressor.unconsumed_tail:
+            return self.error(413, f"Decompressed request body exceeds the maximum size of {limit} bytes",
+                              "request_too_large")(environ, start_response)
+
+        environ['wsgi.input'] = io.BytesIO(body)
+        environ['CONTENT_LENGTH'] = str(len(body))
+        del environ['HTTP_CONTENT_ENCODING']
+        return self.wsgi_app(environ, start_response)
+
+    @staticmethod
+    def error(status: int, message: str, code: str) -> Response:
+        """Build an OpenAI-style error outside of a Flask request context."""
+        return Response(json.dumps({
+            "error": {"message": message, "type": "invalid_request_error", "param": None, "code": code}
+        }), status=status, mimetype='application/json')
+
+# Registered first, so every other hook reads the request's config
+@api.before_app_request
+def bind_config_snapshot() -> None:
+    """Pin the current config, with this app's settings, for the rest of this request."""
+    settings = current_app.config.get('MOCK_SETTINGS')
+    config.local.module = ConfigOverlay(config.current, settings) if settings else config.current
+
+# W3C trace context: version-trace_id-parent_id-flags
+TRACEPARENT_PATTERN = re.compile(r'^([0-9a-f]{2})-([0-9a-f]{32})-([0-9a-f]{16})-([0-9a-f]{2})$')
+
+@api.before_app_request
+def start_trace() -> None:
+    """Continue the caller's `traceparent` as a child span, or start a new trace."""
+    if not config.TRACE_CONTEXT:
+        return
+    match = TRACEPARENT_PATTERN.match(request.headers.get('traceparent', '').strip().lower())
+    if match and match.group(1) != 'ff' and match.group(2) != '0' * 32 and match.group(3) != '0' * 16:
+        g.trace_id, g.parent_span_id, g.trace_flags = match.group(2), match.group(3), match.group(4)
+    else:
+        g.trace_id, g.parent_span_id, g.trace_flags = os.urandom(16).hex(), None, '01'
+    g.span_id = os.urandom(8).hex()
+    g.span_started = time.monotonic()
+
+@api.after_app_request
+def finish_trace(response: Response) -> Response:
+    """Log the request's span and return its context in `traceparent`/`tracestate`."""
+    if not config.TRACE_CONTEXT or 'trace_id' not in g:
+        return response
+    logger.info("span %s %s trace_id=%s span_id=%s parent_span_id=%s status=%d duration_ms=%.1f",
+                request.method, request.path, g.trace_id, g.span_id, g.parent_span_id or '-',
+                response.status_code, (time.monotonic() - g.span_started) * 1000)
+    response.headers['traceparent'] = f"00-{g.trace_id}-{g.span_id}-{g.trace_flags}"
+    if request.headers.get('tracestate'):
+        response.headers['tracestate'] = request.headers['tracestate']
+    return response
+
+def finish_in_flight_request() -> None:
+    global IN_FLIGHT_REQUESTS
+    with IN_FLIGHT_LOCK:
+        IN_FLIGHT_REQUESTS -= 1
+
+@api.before_app_request
+def count_in_flight_request() -> None:
+    """Count the request until its response is sent, so a shutdown can wait for it."""
+    global IN_FLIGHT_REQUESTS
+    with IN_FLIGHT_LOCK:
+        IN_FLIGHT_REQUESTS += 1
+    g.in_flight = True
+
+@api.after_app_request
+def count_response_until_sent(response: Response) -> Response:
+    """Keep the request counted until the server has written its body, streamed or not."""
+    if g.pop('in_flight', False):
+        response.call_on_close(finish_in_flight_request)
+    return response
+
+@api.teardown_app_request
+def uncount_in_flight_request(error) -> None:
+    if g.pop('in_flight', False):
+        finish_in_flight_request()
+
+@api.after_app_request
+def keep_config_snapshot(response: Response) -> Response:
+    """Keep a streamed body on its request's config, even after a reload."""
+    if response.is_streamed:
+        snapshot, chunks = config.local.module, response.response
+
+        def iterate():
+            iterator = iter(chunks)
+            try:
+                while True:
+                    previous, config.local.module = getattr(config.local, 'module', None), snapshot
+                    try:
+                        chunk = next(iterator)
+                    except StopIteration:
+                        return
+                    finally:
+                        config.local.module = previous
+                    yield chunk
+            finally:
+                # A client disconnect closes this wrapper; pass that on to the stream
+                if hasattr(iterator, 'close'):
+                    iterator.close()
+
+        response.response = iterate()
+    return response
+
+def eager_flush(chunks):
+    """Yield to the server thread after every chunk so it's written before the next is made."""
+    iterator = iter(chunks)
+    try:
+        for chunk in iterator:
+            yield chunk
+            time.sleep(0)
+    finally:
+        if hasattr(iterator, 'close'):
+            iterator.close()
+
+@api.after_app_request
+def flush_streams_eagerly(response: Response) -> Response:
+    """With STREAM_EAGER_FLUSH, send streamed chunks on the wire as soon as they're produced."""
+    if config.STREAM_EAGER_FLUSH and response.is_streamed:
+        # Nagle's algorithm would hold small chunks back to coalesce them
+        sock = request.environ.get('werkzeug.socket')
+        if sock is not None:
+            try:
+                sock.setsockopt(socket.IPPROTO_TCP, socket.TCP_NODELAY, 1)
+            except OSError:
+                pass
+        # Ask buffering proxies such as nginx to pass chunks straight through
+        response.headers['X-Accel-Buffering'] = 'no'
+        response.response = eager_flush(response.response)
+    return response
+
+@api.teardown_app_request
+def release_config_snapshot(error) -> None:
+    config.local.module = None
+
+@api.after_app_request
+def add_content_checksum(response: Response) -> Response:
+    """Send the SHA-256 of complete (non-streamed) bodies when CONTENT_CHECKSUM is on."""
+    if config.CONTENT_CHECKSUM and not response.is_streamed:
+        response.headers['x-mock-content-sha256'] = hashlib.sha256(response.get_data()).hexdigest()
+    return response
+
+@api.app_errorhandler(404)
 def not_found(error):
     return jsonify({"error": {"message": "Endpoint not found", "type": "not_found"}}), 404
 
-@app.errorhandler(405)
+@api.app_errorhandler(405)
 def method_not_allowed(error):
     return jsonify({"error": {"message": "Method not allowed", "type": "method_not_allowed"}}), 405
 
+# Read by the shared token bucket's background threads, so no one app can override them
+PROCESS_WIDE_SETTINGS = ('RATE_LIMIT_SETTINGS', 'RATE_LIMIT_REFILL_INTERVAL_MS',
+                         'RATE_LIMIT_WATCHDOG', 'RATE_LIMIT_WATCHDOG_RESTART')
+
+def build_app(settings: Optional[Dict[str, Any]] = None) -> Flask:
+    """Create the mock API as a Flask app, e.g. to embed it in another program's tests.
+
+    settings override config module attributes, such as
+    {"STREAM_EVENT_IDS": True}, for this app's requests only; other apps in
+    the process keep their own. LOG_LEVEL is the exception: the app logger
+    is shared, so the last app built sets it for the whole process.
+    """
+    settings = dict(settings or {})
+    for name in settings:
+        if not hasattr(config, name):
+            raise ValueError(f"Unknown setting: {name}")
+        if name in PROCESS_WIDE_SETTINGS:
+            raise ValueError(f"{name} applies to the whole process; set it in config.py or the environment")
+
+    application = Flask(__name__)
+    application.config['MOCK_SETTINGS'] = settings
+    application.json = NumberFormattingJSONProvider(application)
+    # application.logger is the module's "app" logger, so its level is process-wide
+    logger.setLevel(settings.get('LOG_LEVEL', config.LOG_LEVEL))
+    if not has_level_handler(logger):
+        logger.addHandler(default_handler)
+    CORS(application)
+    application.register_blueprint(api)
+    application.wsgi_app = DecompressRequestMiddleware(application.wsgi_app, settings)
+    return application
+
+app = build_app()
+
+def run(host: str = '0.0.0.0', port: int = PORT, settings: Optional[Dict[str, Any]] = None) -> None:
+    """Serve the API until SIGTERM/SIGINT, then drain in-flight requests.
+
+    Requests get up to SHUTDOWN_GRACE_SECS to finish; streams still running
+    after that are sent a final chunk and [DONE] so the process can exit.
+    """
+    from werkzeug.serving import make_server
+
+    server = make_server(host, port, build_app(settings) if settings else app, threaded=True)
+
+    def request_shutdown(signum, frame):
+        global SHUTDOWN_DEADLINE
+        if SHUTDOWN_DEADLINE is not None:
+            return
+        grace = ConfigOverlay(config, settings or {}).SHUTDOWN_GRACE_SECS
+        SHUTDOWN_DEADLINE = time.monotonic() + grace
+        print(f"\nShutting down, waiting up to {grace}s for "
+              f"{IN_FLIGHT_REQUESTS} in-flight request(s)...", flush=True)
+        # shutdown() blocks until serve_forever() returns, so it can't run here
+        threading.Thread(target=server.shutdown, daemon=True).start()
+
+    # Only the main thread may install handlers; run() on another thread leaves
+    # the embedding program's signal handling alone
+    if threading.current_thread() is threading.main_thread():
+        signal.signal(signal.SIGTERM, request_shutdown)
+        signal.signal(signal.SIGINT, request_shutdown)
+        if hasattr(signal, 'SIGHUP'):
+            signal.signal(signal.SIGHUP, handle_sighup)
+    server.serve_forever()
+
+    in_flight = IN_FLIGHT_REQUESTS
+    # Truncated streams only need a moment to send their final chunks
+    while IN_FLIGHT_REQUESTS > 0 and time.monotonic() < SHUTDOWN_DEADLINE + 1:
+        time.sleep(0.05)
+    forced = FORCED_STREAMS + IN_FLIGHT_REQUESTS
+    print(f"Drained {in_flight - forced} request(s), forcibly closed {forced}", flush=True)
+
 if __name__ == '__main__':
     print(f"Starting Dummy OpenAI API Server on port {PORT}")
     print(f"Required API Key: {REQUIRED_API_KEY}")
@@ -353,12 +2805,18 @@ if __name__ == '__main__':
     print("\nAvailable endpoints:")
     print("  GET  /v1/models - List models")
     print("  POST /v1/chat/completions - Chat completions")
+    print("  POST /v1/completions - Legacy text completions")
     print("  POST /v1/embeddings - Create embeddings")
+    print("  POST /v1/compare - Compare models (non-standard)")
+    print("  POST /v1/messages/count_tokens - Count input tokens (Anthropic-style)")
     print("  GET  /health - Health check")
+    print("  GET  /metrics - Prometheus metrics")
+    print("  GET  /stats - Generated tokens and lifetime budget")
+    print("  POST /scenario/reset - Restart the error schedule")
     print("\nExample usage:")
     print("  curl -H 'Authorization: Bearer sk-dummy' http://localhost:8000/v1/models")
     print("  curl -X POST -H 'Authorization: Bearer sk-dummy' -H 'Content-Type: application/json' \\")
     print("    -d '{\"messages\": [{\"role\": \"user\", \"content\": \"Hello!\"}], \"model\": \"gpt-3.5-turbo\"}' \\")
     print("    http://localhost:8000/v1/chat/completions")
 
-    app.run(host='0.0.0.0', port=PORT, debug=True)
+    run('0.0.0.0', PORT)
@@ -10,17 +10,48 @@ other customizable behavior.
 import os
 import datetime
 
+def _read_config_file(path: str) -> dict:
+    """Parse KEY=VALUE lines from path; # starts a comment."""
+    settings = {}
+    with open(path, encoding='utf-8') as f:
+        for line in f:
+            line = line.strip()
+            if line and not line.startswith('#') and '=' in line:
+                key, value = line.split('=', 1)
+                settings[key.strip()] = value.strip()
+    return settings
+
+# Optional file of KEY=VALUE settings that override the environment. It is
+# read again, along with the environment, when the server gets SIGHUP. The
+# settings below read this overlay rather than os.environ, which is left alone.
+CONFIG_FILE = os.getenv('CONFIG_FILE')
+_ENVIRON = {**os.environ, **(_read_config_file(CONFIG_FILE) if CONFIG_FILE else {})}
+
+def _getenv(key: str, default=None):
+    """os.getenv, with CONFIG_FILE values laid over the environment."""
+    return _ENVIRON.get(key, default)
+
 # =============================================================================
 # Server Configuration
 # =============================================================================
 
 # Server settings
-DEFAULT_PORT = int(os.getenv('PORT', 8000))
-DEFAULT_API_KEY = os.getenv('API_KEY', 'sk-dummy')
-DEBUG_MODE = os.getenv('DEBUG', 'False').lower() == 'true'
+DEFAULT_PORT = int(_getenv('PORT', 8000))
+DEFAULT_API_KEY = _getenv('API_KEY', 'sk-dummy')
+DEBUG_MODE = _getenv('DEBUG', 'False').lower() == 'true'
+
+# Comma-separated OpenAI-Organization / OpenAI-Project values to accept. A request
+# presenting any other organization gets a 401, any other project a 403. Unset
+# accepts anything; accepted values are logged.
+ALLOWED_ORGS = [org.strip() for org in _getenv('ALLOWED_ORGS', '').split(',') if org.strip()]
+ALLOWED_PROJECTS = [proj.strip() for proj in _getenv('ALLOWED_PROJECTS', '').split(',') if proj.strip()]
 
 # Host configuration
-HOST = os.getenv('HOST', '0.0.0.0')
+HOST = _getenv('HOST', '0.0.0.0')
+
+# On SIGTERM/SIGINT, seconds to let in-flight streams finish before they are
+# truncated (final chunk + [DONE]) and the server exits
+SHUTDOWN_GRACE_SECS = float(_getenv('SHUTDOWN_GRACE_SECS', 5))
 
 # =============================================================================
 # Model Configuration
@@ -116,6 +147,52 @@ MODEL_SETTINGS = {
     }
 }
 
+def _parse_model_map(value: str) -> dict:
+    """Parse "model=value,model=value" environment settings."""
+    pairs = (item.split('=', 1) for item in value.split(',') if '=' in item)
+    return {model.strip(): setting.strip() for model, setting in pairs}
+
+# Completion length used when a request doesn't set `max_tokens`. Longer
+# answers are cut off with finish_reason "length". MODEL_DEFAULT_MAX sets it
+# per model, e.g. "gpt-4=256,gpt-3.5-turbo=64"; other models use their
+# default_max_tokens in MODEL_SETTINGS, else the global.
+DEFAULT_MAX_TOKENS = int(_getenv('DEFAULT_MAX_TOKENS', 150))
+MODEL_DEFAULT_MAX = {
+    model: int(limit) for model, limit in _parse_model_map(_getenv('MODEL_DEFAULT_MAX', '')).items()
+}
+
+# Temperature used when a request omits it: MODEL_DEFAULT_TEMPERATURE, e.g.
+# "gpt-4=0.2", else the model's default_temperature in MODEL_SETTINGS
+MODEL_DEFAULT_TEMPERATURE = {
+    model: float(value) for model, value in _parse_model_map(_getenv('MODEL_DEFAULT_TEMPERATURE', '')).items()
+}
+
+# Add the sampling parameters a chat request ran with (defaults applied,
+# out-of-range values clamped) to responses as a non-standard
+# `x_effective_params` field
+ECHO_PARAMS = _getenv('ECHO_PARAMS', 'False').lower() == 'true'
+
+def _parse_model_windows(value: str) -> dict:
+    """Parse "model,model@HH:MM-HH:MM" settings into {model: (start, end) or None}.
+
+    Window bounds are minutes after midnight UTC; no window means always.
+    """
+    windows = {}
+    for item in filter(None, (item.strip() for item in value.split(','))):
+        model, _, window = item.partition('@')
+        if window:
+            start, end = (int(h) * 60 + int(m) for h, m in (bound.split(':') for bound in window.split('-')))
+            windows[model.strip()] = (start, end)
+        else:
+            windows[model.strip()] = None
+    return windows
+
+# Models that are temporarily down (503 with Retry-After), either always or
+# during a daily UTC window, e.g. "gpt-4,gpt-4o@02:00-03:30". MODEL_DEPRECATED
+# lists models that are gone for good (404 model_not_found).
+MODEL_UNAVAILABLE = _parse_model_windows(_getenv('MODEL_UNAVAILABLE', ''))
+MODEL_DEPRECATED = [model.strip() for model in _getenv('MODEL_DEPRECATED', '').split(',') if model.strip()]
+
 # =============================================================================
 # Response Templates and Dummy Data
 # =============================================================================
@@ -193,6 +270,26 @@ TIMING_SETTINGS = {
     "streaming_chunk_delay": 0.01
 }
 
+# How much streaming slows down as more streams run at once, modelling a
+# shared backend. Each stream's rate (1 / streaming_chunk_delay chunks per
+# second) is divided by max(1, active_streams ** THROUGHPUT_ALPHA); 0 disables
+# the slowdown, 1 splits throughput evenly between streams.
+THROUGHPUT_ALPHA = float(_getenv('THROUGHPUT_ALPHA', 0))
+
+# Most SSE events sent per second across all chat streams together (0 = no
+# limit), modelling a server with per-event overhead: the more streams are
+# open, the slower each one gets
+MAX_SSE_EVENTS_PER_SEC = float(_getenv('MAX_SSE_EVENTS_PER_SEC', 0))
+
+# Minimum time (in milliseconds) any completion or embedding request takes,
+# measured until the response (or, when streaming, the first chunk) is sent.
+# A floor on top of the delays above, not a replacement for them.
+MIN_LATENCY_MS = int(_getenv('MIN_LATENCY_MS', 0))
+
+# Most candidates generated at once for requests with `n` > 1; each one takes
+# the chat completion delay, so they are generated concurrently
+MAX_PARALLEL_CANDIDATES = int(_getenv('MAX_PARALLEL_CANDIDATES', 16))
+
 # Processing time multipliers for different model types
 MODEL_PROCESSING_MULTIPLIERS = {
     "gpt-3.5-turbo": 1.0,
@@ -231,27 +328,95 @@ CONTENT_TRIGGERS = {
 # =============================================================================
 
 # Log levels: DEBUG, INFO, WARNING, ERROR
-LOG_LEVEL = os.getenv('LOG_LEVEL', 'INFO')
+LOG_LEVEL = _getenv('LOG_LEVEL', 'INFO')
 
 # Enable request/response logging
-LOG_REQUESTS = os.getenv('LOG_REQUESTS', 'False').lower() == 'true'
-LOG_RESPONSES = os.getenv('LOG_RESPONSES', 'False').lower() == 'true'
+LOG_REQUESTS = _getenv('LOG_REQUESTS', 'False').lower() == 'true'
+LOG_RESPONSES = _getenv('LOG_RESPONSES', 'False').lower() == 'true'
+
+# Enable performance metrics (served at /metrics)
+ENABLE_METRICS = _getenv('ENABLE_METRICS', 'True').lower() == 'true'
 
-# Enable performance metrics
-ENABLE_METRICS = os.getenv('ENABLE_METRICS', 'True').lower() == 'true'
+# Log a warning, and count mock_stream_backpressure_total, whenever a client
+# takes longer than this to accept a streamed event (a slow consumer)
+BACKPRESSURE_LOG_MS = float(_getenv('BACKPRESSURE_LOG_MS', 250))
 
 # =============================================================================
 # Rate Limiting and Quotas
 # =============================================================================
 
-# Note: Rate limiting not implemented in this dummy version
-# These settings are here for future extension
+# Only tokens_per_minute is enforced, and only with RATE_LIMIT_ENABLED; the
+# other limits are here for future extension
 RATE_LIMIT_SETTINGS = {
     "requests_per_minute": 60,
-    "tokens_per_minute": 10000,
+    "tokens_per_minute": int(_getenv('RATE_LIMIT_TPM', 10000)),
     "requests_per_day": 1000
 }
 
+# Enforce tokens_per_minute on chat completions. Each request reserves its
+# prompt tokens plus max_tokens from a bucket that refills continuously;
+# when it runs dry the request gets a 429 whose Retry-After says when enough
+# tokens will be back.
+RATE_LIMIT_ENABLED = _getenv('RATE_LIMIT_ENABLED', 'False').lower() == 'true'
+
+# How often the background refiller tops up the token bucket
+RATE_LIMIT_REFILL_INTERVAL_MS = int(_getenv('RATE_LIMIT_REFILL_INTERVAL_MS', 100))
+
+# Log an error when the refiller hasn't run for twice its interval, and with
+# RATE_LIMIT_WATCHDOG_RESTART start a fresh one so the bucket can't stay empty
+RATE_LIMIT_WATCHDOG = _getenv('RATE_LIMIT_WATCHDOG', 'True').lower() == 'true'
+RATE_LIMIT_WATCHDOG_RESTART = _getenv('RATE_LIMIT_WATCHDOG_RESTART', 'True').lower() == 'true'
+
+# Accept W3C `traceparent`/`tracestate` headers (or start a new trace), log
+# each request as a span with its trace id, and send the server span's
+# context back in `traceparent`. Spans are only logged; nothing is exported.
+TRACE_CONTEXT = _getenv('TRACE_CONTEXT', 'False').lower() == 'true'
+
+# Requests may pick their streaming speed with a model suffix, e.g.
+# `gpt-4@tps=100`. The suffix is stripped before the model is used, and only
+# echoed back in responses with ECHO_MODEL_SUFFIX.
+ECHO_MODEL_SUFFIX = _getenv('ECHO_MODEL_SUFFIX', 'False').lower() == 'true'
+
+# Completion tokens the process may generate before every generating request
+# gets a 429 insufficient_quota until restart, like a prepaid budget (0 = no cap)
+LIFETIME_TOKEN_BUDGET = int(_getenv('LIFETIME_TOKEN_BUDGET', 0))
+
+# Share (0-1) of a request's `prediction` (predicted outputs) that is accepted:
+# those leading words open the answer and stream without delay, the rest of
+# the prediction is reported as rejected_prediction_tokens
+PREDICTION_ACCEPT_RATE = float(_getenv('PREDICTION_ACCEPT_RATE', 0.5))
+
+# Serve chat requests one service_tier lower than requested (priority ->
+# default -> flex) while at least SERVICE_TIER_DOWNGRADE_STREAMS streams are
+# open (0 disables), or at random with SERVICE_TIER_DOWNGRADE_RATE (0-1).
+# Downgraded responses carry an x-mock-service-tier-downgrade header.
+SERVICE_TIER_DOWNGRADE_STREAMS = int(_getenv('SERVICE_TIER_DOWNGRADE_STREAMS', 0))
+SERVICE_TIER_DOWNGRADE_RATE = float(_getenv('SERVICE_TIER_DOWNGRADE_RATE', 0))
+
+# Fraction of requests (0-1) that fail with a random 429, 500 or 503, for
+# testing client retry logic. ERROR_RATE applies everywhere; the per-route
+# variables override it for one group of endpoints.
+ERROR_RATE = float(_getenv('ERROR_RATE', 0))
+ROUTE_ERROR_RATES = {
+    route: float(_ENVIRON[f'ERROR_RATE_{route.upper()}'])
+    for route in ('chat', 'embeddings', 'models')
+    if f'ERROR_RATE_{route.upper()}' in _ENVIRON
+}
+
+def _parse_error_schedule(value: str) -> list:
+    """Parse "ok,429,500" into [None, 429, 500]; None means the request succeeds."""
+    schedule = []
+    for item in filter(None, (item.strip().lower() for item in value.split(','))):
+        if item not in ('ok', '429', '500', '503'):
+            raise ValueError(f"ERROR_SCHEDULE entries must be ok, 429, 500 or 503, got {item!r}")
+        schedule.append(None if item == 'ok' else int(item))
+    return schedule
+
+# A fixed sequence of outcomes that requests cycle through instead of drawing
+# from ERROR_RATE, e.g. "ok,ok,429,500,ok", for fully reproducible retry tests.
+# POST /scenario/reset starts it over from the first entry.
+ERROR_SCHEDULE = _parse_error_schedule(_getenv('ERROR_SCHEDULE', ''))
+
 # =============================================================================
 # Validation Rules
 # =============================================================================
@@ -265,10 +430,37 @@ VALIDATION_LIMITS = {
     "max_batch_size": 100  # for embeddings
 }
 
+# With AUTO_TRUNCATE, chat prompts over PROMPT_TOKEN_LIMIT lose their oldest
+# non-system messages until they fit, and usage reports the smaller prompt.
+# TRUNCATION_HEADER marks those responses with `x-mock-truncated: true`.
+AUTO_TRUNCATE = _getenv('AUTO_TRUNCATE', 'False').lower() == 'true'
+PROMPT_TOKEN_LIMIT = int(_getenv('PROMPT_TOKEN_LIMIT', VALIDATION_LIMITS["max_input_tokens"]))
+TRUNCATION_HEADER = _getenv('TRUNCATION_HEADER', 'True').lower() == 'true'
+
+# Largest request body accepted, in bytes (larger bodies get a 413)
+MAX_REQUEST_BYTES = int(_getenv('MAX_REQUEST_BYTES', 4 * 1024 * 1024))
+
+# Reject out-of-range or mistyped request parameters (temperature, n, top_p,
+# ...) with the same 400 error, including `error.param`, the real API returns.
+# When off, only required fields are checked and other values are accepted.
+STRICT_VALIDATION = _getenv('STRICT_VALIDATION', 'False').lower() == 'true'
+
+# Models that reject requests setting both `max_tokens` and
+# `max_completion_tokens`, as the real API does for newer models, e.g.
+# "o1,o3-mini". Other models accept both and use max_completion_tokens.
+MAX_COMPLETION_TOKENS_ONLY_MODELS = [
+    model.strip() for model in _getenv('MAX_COMPLETION_TOKENS_ONLY_MODELS', '').split(',') if model.strip()
+]
+
 # =============================================================================
 # Response Formatting
 # =============================================================================
 
+# How numbers are written in JSON responses: "default" (Python's shortest
+# form, e.g. 1e-05) or "decimal" (no exponents, integral floats as integers)
+# for clients whose parsers reject scientific notation
+JSON_NUMBER_FORMAT = _getenv('JSON_NUMBER_FORMAT', 'default')
+
 # Enable markdown formatting in responses
 ENABLE_MARKDOWN = True
 
@@ -279,6 +471,87 @@ STREAMING_SETTINGS = {
     "include_usage_in_stream": False
 }
 
+# Seconds of "thinking" placeholder events streamed before the first content
+# delta, for testing thinking indicators (0 disables). The placeholders are
+# empty deltas, or `reasoning_content: "."` deltas with the "reasoning" style.
+THINKING_PLACEHOLDER_SECS = float(_getenv('THINKING_PLACEHOLDER_SECS', 0))
+THINKING_PLACEHOLDER_STYLE = _getenv('THINKING_PLACEHOLDER_STYLE', 'empty')  # "empty" or "reasoning"
+THINKING_PLACEHOLDER_INTERVAL_MS = int(_getenv('THINKING_PLACEHOLDER_INTERVAL_MS', 250))
+
+# Language of generated responses: en (the canned replies), es, de or ja.
+# The x-mock-lang header overrides it per request
+RESPONSE_LANGUAGE = _getenv('RESPONSE_LANGUAGE', 'en')
+
+# When chat replies use an array of content parts (one text part per paragraph,
+# with url_citation annotations) instead of a string: "never", "mirror" (when
+# the last user message used parts, e.g. text + image) or "always". Streams
+# then send `delta.content` as arrays of indexed parts.
+MULTIPART_CONTENT = _getenv('MULTIPART_CONTENT', 'never')
+
+# Push every streamed chunk to the client as soon as it is produced: turn off
+# Nagle's algorithm on the connection, send `X-Accel-Buffering: no` to
+# proxies, and yield to the server thread after each chunk. Lowest latency
+# per chunk, at the cost of more, smaller TCP packets and more thread switches.
+STREAM_EAGER_FLUSH = _getenv('STREAM_EAGER_FLUSH', 'False').lower() == 'true'
+
+# Add a random-length `obfuscation` padding field to every streamed chunk, as
+# OpenAI does to mitigate timing attacks; clients should ignore it
+STREAM_OBFUSCATION = _getenv('STREAM_OBFUSCATION', 'False').lower() == 'true'
+
+# What to do with `stream: true` requests from HTTP/1.0 clients, which can't
+# receive chunked streams: "buffer" answers with a regular non-streaming
+# completion, "error" rejects the request with a 400
+HTTP10_STREAM = _getenv('HTTP10_STREAM', 'buffer')
+
+# Reject requests whose `stream` flag contradicts their Accept header (asking
+# for text/event-stream without `stream: true`, or vice versa) with a 400, to
+# surface client bugs. Off by default, which follows `stream` alone.
+STRICT_STREAM_NEGOTIATION = _getenv('STRICT_STREAM_NEGOTIATION', 'False').lower() == 'true'
+
+# Directory of recorded `.sse` transcripts replayed for streaming requests,
+# picked by the x-mock-transcript header or by request hash (unset disables).
+# SSE_REPLAY_SCALE multiplies the recorded gaps between events (0.5 = twice as fast).
+SSE_TRANSCRIPT_DIR = _getenv('SSE_TRANSCRIPT_DIR', '')
+SSE_REPLAY_SCALE = float(_getenv('SSE_REPLAY_SCALE', 1.0))
+
+# Send an `x-mock-content-sha256` header with the SHA-256 of every non-streamed
+# body. Streams end with a `: x-mock-content-sha256=<hex>` SSE comment hashing
+# the concatenated content deltas instead, since trailers aren't supported.
+CONTENT_CHECKSUM = _getenv('CONTENT_CHECKSUM', 'False').lower() == 'true'
+
+# Attach an incrementing SSE `id:` to every streamed event. A client that
+# reconnects with a `Last-Event-ID` header resumes after that event instead
+# of starting a new completion.
+STREAM_EVENT_IDS = _getenv('STREAM_EVENT_IDS', 'False').lower() == 'true'
+
+# Number of streamed completions remembered for `Last-Event-ID` resumption
+STREAM_SESSION_LIMIT = int(_getenv('STREAM_SESSION_LIMIT', 1000))
+
+# Number of `store: true` completions kept for GET /v1/chat/completions/{id}
+STORED_COMPLETION_LIMIT = int(_getenv('STORED_COMPLETION_LIMIT', 1000))
+
+# Agent mode: every reply starts with a `reasoning_content` segment. Requests
+# with tools then get a tool call (finish_reason "tool_calls"), and requests
+# ending in a tool result get a content answer. Usage counts the reasoning
+# in completion_tokens and in completion_tokens_details.reasoning_tokens.
+REASONING_SEGMENTS = _getenv('REASONING_SEGMENTS', 'False').lower() == 'true'
+
+# Answer function calls in the deprecated shape (`message.function_call`,
+# finish_reason "function_call") instead of `tool_calls`, even for requests
+# that declare `tools`, for regression-testing legacy client code
+LEGACY_FUNCTION_CALL = _getenv('LEGACY_FUNCTION_CALL', 'False').lower() == 'true'
+
+# Serve the pre-models `/v1/engines/{engine}/completions` path, treating the
+# engine as the model, for testing very old client integrations
+LEGACY_ENGINES = _getenv('LEGACY_ENGINES', 'False').lower() == 'true'
+
+# A last user message equal to PING_MARKER is answered with PONG_RESPONSE
+# immediately, skipping simulated delays, rate limits and fault injection, so test
+# harnesses can probe the full chat path cheaply. Set PING_MARKER to an empty
+# string to disable.
+PING_MARKER = _getenv('PING_MARKER', '__PING__')
+PONG_RESPONSE = _getenv('PONG_RESPONSE', '__PONG__')
+
 # =============================================================================
 # Custom Response Templates
 # =============================================================================
@@ -302,14 +575,16 @@ CUSTOM_RESPONSE_TEMPLATES = {
     ]
 }
 
+# When set, every chat reply is this template with `{{name}}` placeholders filled
+# from the request: user_name (the `user` field, or "user"), last_message,
+# model and message_count. Values are inserted as-is; JSON escaping happens
+# when the response is serialized.
+RESPONSE_TEMPLATE = _getenv('RESPONSE_TEMPLATE', '')
+
 # =============================================================================
 # Utility Functions
 # =============================================================================
 
-def get_model_settings(model_id: str) -> dict:
-    """Get settings for a specific model."""
-    return MODEL_SETTINGS.get(model_id, {})
-
 def get_response_by_context(message: str, context: str = "general") -> str:
     """Get a response based on message context."""
     message_lower = message.lower()
@@ -365,31 +640,12 @@ def create_dummy_embedding(text: str, dimension: int = None) -> list:
 
     return embedding
 
-def get_processing_delay(model_id: str, request_type: str = "chat") -> float:
-    """Get appropriate processing delay based on model and request type."""
-    import random
-
-    if request_type == "embedding":
-        base_delay = TIMING_SETTINGS["embedding_delay"]
-    elif request_type == "chat":
-        base_delay = random.uniform(
-            TIMING_SETTINGS["chat_completion_min_delay"],
-            TIMING_SETTINGS["chat_completion_max_delay"]
-        )
-        # Apply model multiplier
-        multiplier = MODEL_PROCESSING_MULTIPLIERS.get(model_id, 1.0)
-        base_delay *= multiplier
-    else:
-        base_delay = TIMING_SETTINGS["model_list_delay"]
-
-    return base_delay
-
 # =============================================================================
 # Environment-specific Overrides
 # =============================================================================
 
 # Development settings
-if os.getenv('ENVIRONMENT') == 'development':
+if _getenv('ENVIRONMENT') == 'development':
     TIMING_SETTINGS.update({
         "chat_completion_min_delay": 0.1,
         "chat_completion_max_delay": 0.5,
@@ -401,7 +657,7 @@ if os.getenv('ENVIRONMENT') == 'development':
     LOG_RESPONSES = True
 
 # Testing settings
-elif os.getenv('ENVIRONMENT') == 'testing':
+elif _getenv('ENVIRONMENT') == 'testing':
     TIMING_SETTINGS.update({
         "chat_completion_min_delay": 0.01,
         "chat_completion_max_delay": 0.05,
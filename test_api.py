@@ -6,17 +6,20 @@ Quick test script to verify the dummy OpenAI API installation and basic function
 This script checks if the server is running and tests key endpoints.
 
 Usage:
-    python test_api.py
+    python test_api.py            # Live server tests + in-process feature tests
+    python test_api.py --local    # In-process feature tests only (no server needed)
 
 Requirements:
     - Dummy API server should be running on localhost:8000
     - requests library (pip install requests)
 """
 
+import os
 import sys
 import json
 import time
-from typing import Dict, Any, Optional
+from contextlib import contextmanager
+from typing import Dict, Any, List, Optional
 
 def test_imports():
     """Test if required libraries are available."""
@@ -292,6 +295,2812 @@ def test_error_handling() -> bool:
         print(f"✗ Error handling test failed - expected 400, got {response.status_code}")
         return False
 
+# =============================================================================
+# In-process feature tests
+#
+# These drive the Flask app directly through its test client, so they can
+# change config.py settings per test and don't need a running server.
+# =============================================================================
+
+LOCAL_HEADERS = {
+    "Authorization": "Bearer sk-dummy",
+    "Content-Type": "application/json"
+}
+
+# Timing settings that remove every simulated delay
+NO_DELAY_TIMINGS = {
+    "chat_completion_min_delay": 0.0,
+    "chat_completion_max_delay": 0.0,
+    "embedding_delay": 0.0,
+    "model_list_delay": 0.0,
+    "streaming_chunk_delay": 0.0
+}
+
+def local_client():
+    """Return a Flask test client for the app, using the fast testing timings."""
+    os.environ.setdefault('ENVIRONMENT', 'testing')
+    from app import app
+    return app.test_client()
+
+@contextmanager
+def override_config(**settings):
+    """Temporarily replace values in config.py for the duration of a test."""
+    import config
+
+    saved = {name: getattr(config, name) for name in settings}
+    for name, value in settings.items():
+        setattr(config, name, value)
+    try:
+        yield
+    finally:
+        for name, value in saved.items():
+            setattr(config, name, value)
+
+@contextmanager
+def capture_logs(level: Optional[int] = None):
+    """Collect the messages the app logs, optionally at a lower level, during a test."""
+    import logging
+    from app import app as flask_app
+
+    class Capture(logging.Handler):
+        def __init__(self):
+            super().__init__()
+            self.messages = []
+
+        def emit(self, record):
+            self.messages.append(record.getMessage())
+
+    capture, previous = Capture(), flask_app.logger.level
+    flask_app.logger.addHandler(capture)
+    if level is not None:
+        flask_app.logger.setLevel(level)
+    try:
+        yield capture.messages
+    finally:
+        flask_app.logger.removeHandler(capture)
+        flask_app.logger.setLevel(previous)
+
+def parse_sse_events(lines) -> List[Dict[str, Any]]:
+    """Parse raw SSE text lines into events with optional `id` and `data`."""
+    events, current = [], {}
+    for line in lines:
+        if isinstance(line, bytes):
+            line = line.decode('utf-8')
+        for part in line.split('\n'):
+            if part == '':
+                if current:
+                    events.append(current)
+                    current = {}
+            elif part.startswith('id: '):
+                current['id'] = part[4:]
+            elif part.startswith('data: '):
+                current['data'] = part[6:]
+    if current:
+        events.append(current)
+    return events
+
+def event_content(event: Dict[str, Any]) -> str:
+    """Return the content delta carried by a streamed chunk event, if any."""
+    if event.get('data', '[DONE]') == '[DONE]':
+        return ''
+    choices = json.loads(event['data']).get('choices') or [{}]
+    return choices[0].get('delta', {}).get('content') or ''
+
+def test_stream_resume_with_last_event_id() -> bool:
+    """Test that a reconnect with Last-Event-ID continues without duplicates."""
+    print("\nTesting stream resumption with Last-Event-ID...")
+
+    client = local_client()
+    payload = {
+        "model": "gpt-3.5-turbo",
+        "messages": [{"role": "user", "content": "Tell me a story"}],
+        "stream": True
+    }
+
+    with override_config(STREAM_EVENT_IDS=True):
+        # Read a few events, then drop the connection
+        response = client.post('/v1/chat/completions', headers=LOCAL_HEADERS, json=payload)
+        first_events = []
+        for raw in response.response:
+            first_events.extend(parse_sse_events([raw]))
+            if len(first_events) == 5:
+                break
+        response.close()
+
+        last_id = first_events[-1].get('id')
+        if not last_id:
+            print("✗ Streamed events carry no id field")
+            return False
+
+        resumed = client.post(
+            '/v1/chat/completions',
+            headers={**LOCAL_HEADERS, "Last-Event-ID": last_id},
+            json=payload
+        )
+        resumed_events = parse_sse_events(resumed.response)
+
+    completion_id, _, last_index = last_id.rpartition(':')
+    first_resumed_id = resumed_events[0].get('id', '')
+    if first_resumed_id != f"{completion_id}:{int(last_index) + 1}":
+        print(f"✗ Resumed at {first_resumed_id}, expected the event after {last_id}")
+        return False
+
+    delivered = ''.join(event_content(e) for e in first_events + resumed_events)
+
+    import app as app_module
+    expected = app_module.STREAM_SESSIONS[completion_id]["choices"][0]["message"]["content"]
+    if delivered != expected:
+        print("✗ Reassembled content has gaps or duplicated tokens")
+        return False
+
+    print(f"✓ Resumed after {last_id} without duplicating delivered tokens")
+    return True
+
+def test_min_latency_floor() -> bool:
+    """Test that no response is returned faster than MIN_LATENCY_MS."""
+    print("\nTesting minimum latency floor...")
+
+    client = local_client()
+    payload = {
+        "model": "gpt-3.5-turbo",
+        "messages": [{"role": "user", "content": "Hello!"}]
+    }
+
+    with override_config(MIN_LATENCY_MS=300, TIMING_SETTINGS=NO_DELAY_TIMINGS):
+        started = time.monotonic()
+        client.post('/v1/chat/completions', headers=LOCAL_HEADERS, json=payload)
+        non_streaming = time.monotonic() - started
+
+        started = time.monotonic()
+        response = client.post('/v1/chat/completions', headers=LOCAL_HEADERS,
+                               json={**payload, "stream": True})
+        next(iter(response.response))
+        first_chunk = time.monotonic() - started
+        response.close()
+
+        started = time.monotonic()
+        client.post('/v1/embeddings', headers=LOCAL_HEADERS,
+                    json={"model": "text-embedding-ada-002", "input": "floor"})
+        embedding = time.monotonic() - started
+
+    for label, elapsed in [("non-streaming", non_streaming), ("first chunk", first_chunk),
+                           ("embedding", embedding)]:
+        if elapsed < 0.3:
+            print(f"✗ {label} returned after {elapsed * 1000:.0f}ms, below the 300ms floor")
+            return False
+
+    print("✓ Non-streaming, first-chunk and embedding latency all respect the floor")
+    return True
+
+def test_tool_choice_required() -> bool:
+    """Test that tool_choice "required" always produces a tool call."""
+    print("\nTesting tool_choice: required...")
+
+    client = local_client()
+    payload = {
+        "model": "gpt-4",
+        "messages": [{"role": "user", "content": "Hello!"}],
+        "tools": [{
+            "type": "function",
+            "function": {
+                "name": "get_weather",
+                "parameters": {
+                    "type": "object",
+                    "properties": {
+                        "location": {"type": "string"},
+                        "unit": {"type": "string", "enum": ["celsius", "fahrenheit"]}
+                    },
+                    "required": ["location"]
+                }
+            }
+        }],
+        "tool_choice": "required"
+    }
+
+    with override_config(TIMING_SETTINGS=NO_DELAY_TIMINGS):
+        for _ in range(10):
+            data = client.post('/v1/chat/completions', headers=LOCAL_HEADERS, json=payload).get_json()
+            choice = data["choices"][0]
+            calls = choice["message"].get("tool_calls") or []
+            if choice["finish_reason"] != "tool_calls" or not calls:
+                print(f"✗ Got a text answer instead of a tool call: {choice}")
+                return False
+            arguments = json.loads(calls[0]["function"]["arguments"])
+            if calls[0]["function"]["name"] != "get_weather" or arguments.get("unit") != "celsius":
+                print(f"✗ Unexpected tool call: {calls[0]}")
+                return False
+
+        events = parse_sse_events(client.post(
+            '/v1/chat/completions', headers=LOCAL_HEADERS, json={**payload, "stream": True}
+        ).response)
+
+    chunks = [json.loads(e['data']) for e in events if e.get('data') != '[DONE]']
+    streamed_args = ''.join(
+        call["function"].get("arguments", "")
+        for chunk in chunks
+        for call in chunk["choices"][0]["delta"].get("tool_calls", [])
+    )
+    if chunks[-1]["choices"][0]["finish_reason"] != "tool_calls" or not json.loads(streamed_args):
+        print("✗ Streamed tool call did not finish with tool_calls")
+        return False
+
+    for tools, param in (([{"type": "function", "function": "get_weather"}], "tools[0].function"),
+                         ([{"type": "function", "function": {}}], "tools[0].function"),
+                         ([{"type": "code_interpreter"}], "tool_choice")):
+        response = client.post('/v1/chat/completions', headers=LOCAL_HEADERS,
+                               json={**payload, "tools": tools})
+        if response.status_code != 400 or response.get_json()["error"]["param"] != param:
+            print(f"✗ Unusable tools {tools} got {response.status_code}: {response.get_json()}")
+            return False
+
+    print("✓ Every response carried a tool call with finish_reason tool_calls")
+    return True
+
+def test_validation_error_params() -> bool:
+    """Test that 400s name the offending field in error.param."""
+    print("\nTesting validation error params...")
+
+    client = local_client()
+    base = {"model": "gpt-3.5-turbo", "messages": [{"role": "user", "content": "Hi"}]}
+    cases = [
+        ({"model": "gpt-3.5-turbo"}, "messages"),
+        ({**base, "temperature": 5}, "temperature"),
+        ({**base, "temperature": "hot"}, "temperature"),
+        ({**base, "n": 0}, "n"),
+        ({**base, "top_p": 1.5}, "top_p"),
+        ({**base, "max_tokens": 2.5}, "max_tokens"),
+        ({**base, "stop": ["a", "b", "c", "d", "e"]}, "stop"),
+        ({**base, "messages": [{"content": "no role"}]}, "messages[0].role"),
+        ({**base, "tool_choice": "required"}, "tool_choice"),
+    ]
+
+    with override_config(STRICT_VALIDATION=True, TIMING_SETTINGS=NO_DELAY_TIMINGS):
+        for payload, expected_param in cases:
+            response = client.post('/v1/chat/completions', headers=LOCAL_HEADERS, json=payload)
+            error = response.get_json().get("error", {})
+            if response.status_code != 400 or error.get("param") != expected_param:
+                print(f"✗ Expected 400 with param {expected_param!r}, got "
+                      f"{response.status_code} with param {error.get('param')!r}")
+                return False
+            if error.get("type") != "invalid_request_error":
+                print(f"✗ Unexpected error type: {error.get('type')}")
+                return False
+
+        valid = client.post('/v1/chat/completions', headers=LOCAL_HEADERS,
+                            json={**base, "temperature": 1.2, "n": 1})
+        if valid.status_code != 200:
+            print(f"✗ Valid request rejected: {valid.get_json()}")
+            return False
+
+    print(f"✓ {len(cases)} invalid requests reported the correct error.param")
+    return True
+
+def test_concurrent_throughput_degradation() -> bool:
+    """Test that per-stream throughput drops as concurrent streams rise."""
+    import threading
+
+    print("\nTesting concurrency-aware throughput degradation...")
+
+    client = local_client()
+    payload = {
+        "model": "gpt-3.5-turbo",
+        "messages": [{"role": "user", "content": "Hello!"}],
+        "stream": True
+    }
+
+    def seconds_per_chunk() -> float:
+        response = client.post('/v1/chat/completions', headers=LOCAL_HEADERS, json=payload)
+        started, chunks = time.monotonic(), 0
+        for _ in response.response:
+            chunks += 1
+        return (time.monotonic() - started) / chunks
+
+    timings = {**NO_DELAY_TIMINGS, "streaming_chunk_delay": 0.005}
+    with override_config(THROUGHPUT_ALPHA=1.0, TIMING_SETTINGS=timings):
+        single = seconds_per_chunk()
+
+        results = []
+        threads = [threading.Thread(target=lambda: results.append(seconds_per_chunk()))
+                   for _ in range(4)]
+        for thread in threads:
+            thread.start()
+        for thread in threads:
+            thread.join()
+
+    concurrent = sum(results) / len(results)
+    # With alpha=1 and 4 streams the formula predicts ~4x slower chunks; allow
+    # slack for streams that finish early and stop counting as active.
+    if concurrent < single * 2:
+        print(f"✗ 4 concurrent streams ran at {concurrent * 1000:.1f}ms/chunk vs "
+              f"{single * 1000:.1f}ms/chunk alone")
+        return False
+
+    print(f"✓ Single stream {single * 1000:.1f}ms/chunk, 4 concurrent "
+          f"{concurrent * 1000:.1f}ms/chunk")
+    return True
+
+def test_stored_completion_etag() -> bool:
+    """Test conditional retrieval of a stored completion with If-None-Match."""
+    print("\nTesting stored completion ETag...")
+
+    client = local_client()
+    payload = {
+        "model": "gpt-3.5-turbo",
+        "messages": [{"role": "user", "content": "Hello!"}],
+        "store": True
+    }
+
+    with override_config(TIMING_SETTINGS=NO_DELAY_TIMINGS):
+        created = client.post('/v1/chat/completions', headers=LOCAL_HEADERS, json=payload)
+    completion_id = created.get_json()["id"]
+
+    first = client.get(f'/v1/chat/completions/{completion_id}', headers=LOCAL_HEADERS)
+    etag = first.headers.get('ETag')
+    if first.status_code != 200 or not etag or first.get_json()["id"] != completion_id:
+        print(f"✗ First fetch returned {first.status_code} with ETag {etag!r}")
+        return False
+    if etag != created.headers.get('ETag'):
+        print("✗ Retrieval ETag differs from the one returned on creation")
+        return False
+
+    second = client.get(f'/v1/chat/completions/{completion_id}',
+                        headers={**LOCAL_HEADERS, "If-None-Match": etag})
+    if second.status_code != 304 or second.get_data():
+        print(f"✗ Conditional fetch returned {second.status_code}, expected an empty 304")
+        return False
+
+    stale = client.get(f'/v1/chat/completions/{completion_id}',
+                       headers={**LOCAL_HEADERS, "If-None-Match": '"stale"'})
+    if stale.status_code != 200:
+        print(f"✗ Non-matching ETag returned {stale.status_code}, expected 200")
+        return False
+
+    print(f"✓ Matching If-None-Match returned 304 for {completion_id}")
+    return True
+
+def fuzz_body(rng) -> bytes:
+    """Produce one arbitrary or malformed chat completion request body."""
+    valid = {
+        "model": "gpt-3.5-turbo",
+        "messages": [{"role": "user", "content": "Hello!"}],
+        "tools": [{"type": "function", "function": {"name": "f", "parameters": {}}}],
+        "tool_choice": "required",
+        "temperature": 0.7,
+        "stream": False
+    }
+    samples = [None, True, 0, -1, 2.5, 1e308, "", "x" * 50, [], [1, "a"], {}, {"a": None}]
+    kind = rng.randrange(5)
+
+    if kind == 0:
+        # Arbitrary bytes
+        return bytes(rng.randrange(256) for _ in range(rng.randrange(64)))
+    if kind == 1:
+        # Valid JSON with characters flipped, dropped or inserted
+        raw = bytearray(json.dumps(valid).encode())
+        for _ in range(rng.randrange(1, 6)):
+            position = rng.randrange(len(raw))
+            action = rng.randrange(3)
+            if action == 0:
+                raw[position] = rng.randrange(256)
+            elif action == 1:
+                del raw[position]
+            else:
+                raw.insert(position, rng.choice(b'{}[]",:\\0'))
+        return bytes(raw)
+    if kind == 2:
+        # Well-formed JSON with fields replaced by values of the wrong type
+        body = json.loads(json.dumps(valid))
+        targets = [body, body["messages"][0], body["tools"][0], body["tools"][0]["function"]]
+        for _ in range(rng.randrange(1, 4)):
+            target = rng.choice(targets)
+            target[rng.choice(list(target))] = rng.choice(samples)
+        return json.dumps(body).encode()
+    if kind == 3:
+        # Duplicate keys, non-finite numbers and deep nesting
+        return rng.choice([
+            b'{"model": "a", "model": "b", "messages": []}',
+            b'{"messages": [{"role": "user", "content": "hi"}], "temperature": NaN}',
+            b'[' * 5000 + b']' * 5000,
+            b'{"messages": ' + b'[' * 3000,
+        ])
+    # Top-level values that aren't objects
+    return json.dumps(rng.choice(samples)).encode()
+
+def test_malformed_body_fuzzing() -> bool:
+    """Fuzz /v1/chat/completions with arbitrary bodies; expect structured errors only.
+
+    Set FUZZ_ITERATIONS for a longer run and FUZZ_SEED to replay a failure.
+    """
+    import random
+
+    print("\nTesting malformed request bodies (fuzzing)...")
+
+    client = local_client()
+    seed = int(os.getenv('FUZZ_SEED', random.randrange(2 ** 32)))
+    iterations = int(os.getenv('FUZZ_ITERATIONS', 300))
+    rng = random.Random(seed)
+
+    with override_config(TIMING_SETTINGS=NO_DELAY_TIMINGS, MAX_REQUEST_BYTES=4096):
+        bodies = [fuzz_body(rng) for _ in range(iterations)] + [b'{}' + b' ' * 5000]
+        for body in bodies:
+            started = time.monotonic()
+            response = client.post('/v1/chat/completions', data=body,
+                                   headers={"Authorization": "Bearer sk-dummy",
+                                            "Content-Type": rng.choice(["application/json", "text/plain"])})
+            elapsed = time.monotonic() - started
+
+            if response.status_code >= 500 or elapsed > 2:
+                print(f"✗ Body {body[:80]!r} got {response.status_code} after {elapsed:.2f}s "
+                      f"(FUZZ_SEED={seed})")
+                return False
+            if response.status_code != 200:
+                error = (response.get_json(silent=True) or {}).get("error")
+                if not isinstance(error, dict) or not error.get("message"):
+                    print(f"✗ Body {body[:80]!r} got an unstructured {response.status_code} "
+                          f"(FUZZ_SEED={seed})")
+                    return False
+
+    print(f"✓ {len(bodies)} malformed bodies all got structured responses (FUZZ_SEED={seed})")
+    return True
+
+def test_thinking_placeholders() -> bool:
+    """Test that thinking placeholder events precede the first content delta."""
+    print("\nTesting thinking placeholder stream...")
+
+    client = local_client()
+    payload = {
+        "model": "gpt-3.5-turbo",
+        "messages": [{"role": "user", "content": "Hello!"}],
+        "stream": True
+    }
+
+    for style in ("empty", "reasoning"):
+        with override_config(TIMING_SETTINGS=NO_DELAY_TIMINGS, THINKING_PLACEHOLDER_SECS=0.3,
+                             THINKING_PLACEHOLDER_INTERVAL_MS=50, THINKING_PLACEHOLDER_STYLE=style):
+            started = time.monotonic()
+            response = client.post('/v1/chat/completions', headers=LOCAL_HEADERS, json=payload)
+            events, first_content_at = [], None
+            for raw in response.response:
+                for event in parse_sse_events([raw]):
+                    events.append(event)
+                    if first_content_at is None and event_content(event):
+                        first_content_at = time.monotonic() - started
+
+        deltas = [json.loads(e['data'])["choices"][0]["delta"]
+                  for e in events if e.get('data') != '[DONE]']
+        first_content = next(i for i, d in enumerate(deltas) if d.get('content'))
+        placeholders = deltas[:first_content]
+        expected = {} if style == "empty" else {"role": "assistant", "content": None,
+                                                "reasoning_content": "."}
+
+        if len(placeholders) < 3 or any(d != expected for d in placeholders):
+            print(f"✗ {style}: expected placeholder deltas before content, got {placeholders[:3]}")
+            return False
+        if first_content_at < 0.3:
+            print(f"✗ {style}: content started after {first_content_at:.2f}s, before the 0.3s window")
+            return False
+        if any(d == expected for d in deltas[first_content:-1]):
+            print(f"✗ {style}: placeholders were mixed into the content")
+            return False
+
+    print("✓ Placeholder events preceded the first content delta in both styles")
+    return True
+
+def test_audio_modality() -> bool:
+    """Test that modalities ["text", "audio"] returns decodable audio and a transcript."""
+    import base64
+    import io
+    import wave
+
+    print("\nTesting audio modality...")
+
+    client = local_client()
+    payload = {
+        "model": "gpt-4o",
+        "messages": [{"role": "user", "content": "Hello!"}],
+        "modalities": ["text", "audio"],
+        "audio": {"voice": "alloy", "format": "wav"}
+    }
+
+    with override_config(TIMING_SETTINGS=NO_DELAY_TIMINGS):
+        data = client.post('/v1/chat/completions', headers=LOCAL_HEADERS, json=payload).get_json()
+        invalid = client.post('/v1/chat/completions', headers=LOCAL_HEADERS,
+                              json={**payload, "modalities": ["text", "video"]})
+
+    audio = data["choices"][0]["message"].get("audio")
+    if not audio or not audio.get("transcript") or not audio.get("id") or not audio.get("expires_at"):
+        print(f"✗ Missing audio fields: {audio}")
+        return False
+
+    try:
+        with wave.open(io.BytesIO(base64.b64decode(audio["data"], validate=True))) as wav:
+            frames = wav.getnframes()
+    except Exception as e:
+        print(f"✗ Audio data is not base64-encoded WAV: {e}")
+        return False
+
+    error = invalid.get_json().get("error", {})
+    if invalid.status_code != 400 or error.get("param") != "modalities[1]":
+        print(f"✗ Unknown modality returned {invalid.status_code} with param {error.get('param')!r}")
+        return False
+
+    print(f"✓ Audio decoded to {frames} WAV frames with a transcript; unknown modality rejected")
+    return True
+
+def free_port() -> int:
+    """Find a TCP port nothing is listening on."""
+    import socket
+
+    with socket.socket() as sock:
+        sock.bind(('127.0.0.1', 0))
+        return sock.getsockname()[1]
+
+def start_server_process(**env):
+    """Start app.py as a subprocess on a free port and wait until it's up."""
+    import subprocess
+    import requests
+
+    port = free_port()
+    process = subprocess.Popen(
+        [sys.executable, os.path.join(os.path.dirname(os.path.abspath(__file__)), 'app.py')],
+        env={**os.environ, "ENVIRONMENT": "testing", "PORT": str(port), **env},
+        stdout=subprocess.PIPE, stderr=subprocess.STDOUT, text=True
+    )
+    for _ in range(100):
+        try:
+            requests.get(f"http://127.0.0.1:{port}/health", timeout=1)
+            return process, port
+        except requests.ConnectionError:
+            time.sleep(0.05)
+    process.kill()
+    raise RuntimeError("server did not start")
+
+def test_shutdown_grace_period() -> bool:
+    """Test that SIGTERM truncates a long stream and exits within the grace window."""
+    import signal
+    import requests
+
+    print("\nTesting shutdown grace period...")
+
+    process, port = start_server_process(SHUTDOWN_GRACE_SECS="1", THINKING_PLACEHOLDER_SECS="60")
+    try:
+        response = requests.post(
+            f"http://127.0.0.1:{port}/v1/chat/completions",
+            headers=LOCAL_HEADERS,
+            json={"model": "gpt-3.5-turbo", "messages": [{"role": "user", "content": "Hi"}],
+                  "stream": True},
+            stream=True, timeout=10
+        )
+        lines = response.iter_lines()
+        next(lines)
+
+        started = time.monotonic()
+        process.send_signal(signal.SIGTERM)
+        events = parse_sse_events(line + b'\n' for line in lines)
+        output, _ = process.communicate(timeout=10)
+        elapsed = time.monotonic() - started
+    finally:
+        if process.poll() is None:
+            process.kill()
+
+    if elapsed > 3:
+        print(f"✗ Server took {elapsed:.1f}s to exit with a 1s grace period")
+        return False
+    if events[-1].get('data') != '[DONE]':
+        print("✗ Truncated stream did not end with [DONE]")
+        return False
+    finish = json.loads(events[-2]['data'])["choices"][0]["finish_reason"]
+    if finish != "length" or "forcibly closed 1" not in output:
+        print(f"✗ Expected a truncation chunk and one forced close, got {finish!r} / {output!r}")
+        return False
+
+    print(f"✓ Server truncated the stream and exited {elapsed:.1f}s after SIGTERM")
+    return True
+
+def test_per_route_error_rates() -> bool:
+    """Test that per-route error rates override the global ERROR_RATE."""
+    print("\nTesting per-route error rates...")
+
+    client = local_client()
+    chat = {"model": "gpt-3.5-turbo", "messages": [{"role": "user", "content": "Hello!"}]}
+    embedding = {"model": "text-embedding-ada-002", "input": "reliable"}
+    requests_per_route = 200
+
+    with override_config(TIMING_SETTINGS=NO_DELAY_TIMINGS, ERROR_RATE=1.0,
+                         ROUTE_ERROR_RATES={"chat": 0.5, "embeddings": 0.0}):
+        chat_errors = sum(
+            client.post('/v1/chat/completions', headers=LOCAL_HEADERS, json=chat).status_code != 200
+            for _ in range(requests_per_route)
+        )
+        embedding_errors = sum(
+            client.post('/v1/embeddings', headers=LOCAL_HEADERS, json=embedding).status_code != 200
+            for _ in range(requests_per_route)
+        )
+        models = client.get('/v1/models', headers=LOCAL_HEADERS)
+
+    if embedding_errors:
+        print(f"✗ Embeddings failed {embedding_errors} times despite ERROR_RATE_EMBEDDINGS=0")
+        return False
+    if not 0.35 <= chat_errors / requests_per_route <= 0.65:
+        print(f"✗ Chat failed {chat_errors}/{requests_per_route} times, expected about half")
+        return False
+    if models.status_code not in (429, 500, 503) or "error" not in models.get_json():
+        print(f"✗ Models route ignored the global ERROR_RATE=1 (got {models.status_code})")
+        return False
+
+    print(f"✓ Chat failed {chat_errors}/{requests_per_route}, embeddings 0/{requests_per_route}")
+    return True
+
+def test_parallel_candidates() -> bool:
+    """Test that n:4 isn't much slower than n:1 and usage sums the choices."""
+    print("\nTesting parallel candidate generation...")
+
+    client = local_client()
+    payload = {"model": "gpt-3.5-turbo", "messages": [{"role": "user", "content": "Hello!"}]}
+    timings = {**NO_DELAY_TIMINGS, "chat_completion_min_delay": 0.2,
+               "chat_completion_max_delay": 0.2}
+
+    with override_config(TIMING_SETTINGS=timings):
+        started = time.monotonic()
+        client.post('/v1/chat/completions', headers=LOCAL_HEADERS, json=payload)
+        single = time.monotonic() - started
+
+        started = time.monotonic()
+        data = client.post('/v1/chat/completions', headers=LOCAL_HEADERS,
+                           json={**payload, "n": 4}).get_json()
+        four = time.monotonic() - started
+
+    choices = data["choices"]
+    if [c["index"] for c in choices] != [0, 1, 2, 3]:
+        print(f"✗ Expected 4 indexed choices, got {[c['index'] for c in choices]}")
+        return False
+
+    completion_tokens = sum(len(c["message"]["content"].split()) for c in choices)
+    usage = data["usage"]
+    if usage["completion_tokens"] != completion_tokens or \
+            usage["total_tokens"] != usage["prompt_tokens"] + completion_tokens:
+        print(f"✗ Usage {usage} doesn't add up to the {completion_tokens} generated tokens")
+        return False
+
+    if four > single * 2:
+        print(f"✗ n:4 took {four:.2f}s vs {single:.2f}s for n:1")
+        return False
+
+    print(f"✓ n:4 took {four:.2f}s vs {single:.2f}s for n:1, usage summed across choices")
+    return True
+
+def test_ping_marker() -> bool:
+    """Test that the ping marker yields the pong response instantly."""
+    print("\nTesting ping marker...")
+
+    client = local_client()
+    payload = {"model": "gpt-4", "messages": [{"role": "user", "content": "__PING__"}]}
+    slow = {**NO_DELAY_TIMINGS, "chat_completion_min_delay": 2.0,
+            "chat_completion_max_delay": 2.0, "streaming_chunk_delay": 1.0}
+
+    with override_config(TIMING_SETTINGS=slow, MIN_LATENCY_MS=2000, ERROR_RATE=1.0,
+                         THINKING_PLACEHOLDER_SECS=2.0):
+        started = time.monotonic()
+        data = client.post('/v1/chat/completions', headers=LOCAL_HEADERS, json=payload).get_json()
+        events = parse_sse_events(client.post('/v1/chat/completions', headers=LOCAL_HEADERS,
+                                              json={**payload, "stream": True}).response)
+        elapsed = time.monotonic() - started
+
+    content = data.get("choices", [{}])[0].get("message", {}).get("content")
+    streamed = ''.join(event_content(e) for e in events)
+    if content != "__PONG__" or streamed != "__PONG__":
+        print(f"✗ Expected __PONG__, got {content!r} / streamed {streamed!r}")
+        return False
+    if elapsed > 0.5:
+        print(f"✗ Ping took {elapsed:.2f}s despite bypassing delays")
+        return False
+
+    print(f"✓ Ping answered with __PONG__ in {elapsed * 1000:.0f}ms (streaming and not)")
+    return True
+
+def test_model_default_max_tokens() -> bool:
+    """Test that models with different default max tokens produce different lengths."""
+    print("\nTesting per-model default max tokens...")
+
+    import config
+    client = local_client()
+    lengths = {}
+    # MODEL_SETTINGS' default_max_tokens applies when MODEL_DEFAULT_MAX has no entry
+    settings = {**config.MODEL_SETTINGS, "gpt-4-turbo": {**config.MODEL_SETTINGS["gpt-4-turbo"],
+                                                         "default_max_tokens": 4}}
+
+    with override_config(TIMING_SETTINGS=NO_DELAY_TIMINGS, DEFAULT_MAX_TOKENS=1000, MODEL_SETTINGS=settings,
+                         MODEL_DEFAULT_MAX={"gpt-4": 3, "gpt-3.5-turbo": 6}):
+        for model in ("gpt-4", "gpt-3.5-turbo", "gpt-4-turbo", "custom-model"):
+            data = client.post('/v1/chat/completions', headers=LOCAL_HEADERS, json={
+                "model": model,
+                "messages": [{"role": "user", "content": "Hello!"}]
+            }).get_json()
+            choice = data["choices"][0]
+            lengths[model] = (data["usage"]["completion_tokens"], choice["finish_reason"])
+
+        explicit = client.post('/v1/chat/completions', headers=LOCAL_HEADERS, json={
+            "model": "gpt-4", "max_tokens": 5,
+            "messages": [{"role": "user", "content": "Hello!"}]
+        }).get_json()["usage"]["completion_tokens"]
+
+    if lengths["gpt-4"] != (3, "length") or lengths["gpt-3.5-turbo"] != (6, "length"):
+        print(f"✗ Unexpected per-model lengths: {lengths}")
+        return False
+    if lengths["gpt-4-turbo"] != (4, "length"):
+        print(f"✗ MODEL_SETTINGS default_max_tokens not honoured: {lengths['gpt-4-turbo']}")
+        return False
+    if lengths["custom-model"][1] != "stop" or explicit != 5:
+        print(f"✗ Global default or explicit max_tokens not honoured: {lengths['custom-model']}, {explicit}")
+        return False
+
+    print("✓ gpt-4 stopped at 3 tokens, gpt-3.5-turbo at 6 and gpt-4-turbo at its MODEL_SETTINGS 4, "
+          "all with finish_reason length")
+    return True
+
+def test_compressed_request_bodies() -> bool:
+    """Test that gzip/deflate bodies are decompressed and zip bombs are refused."""
+    import gzip
+    import zlib
+
+    print("\nTesting compressed request bodies...")
+
+    client = local_client()
+    body = json.dumps({
+        "model": "gpt-3.5-turbo",
+        "messages": [{"role": "user", "content": "Hello!"}]
+    }).encode()
+
+    with override_config(TIMING_SETTINGS=NO_DELAY_TIMINGS, MAX_REQUEST_BYTES=64 * 1024):
+        for encoding, compressed in [("gzip", gzip.compress(body)), ("deflate", zlib.compress(body))]:
+            response = client.post('/v1/chat/completions', data=compressed, headers={
+                **LOCAL_HEADERS, "Content-Encoding": encoding
+            })
+            if response.status_code != 200 or not response.get_json()["choices"][0]["message"]["content"]:
+                print(f"✗ {encoding} body returned {response.status_code}: {response.get_data()[:200]!r}")
+                return False
+
+        bomb = client.post('/v1/chat/completions', data=gzip.compress(b' ' * (10 * 1024 * 1024)),
+                           headers={**LOCAL_HEADERS, "Content-Encoding": "gzip"})
+        corrupt = client.post('/v1/chat/completions', data=b'not gzip at all',
+                              headers={**LOCAL_HEADERS, "Content-Encoding": "gzip"})
+
+    if bomb.status_code != 413 or bomb.get_json()["error"]["code"] != "request_too_large":
+        print(f"✗ Oversized decompressed body returned {bomb.status_code}, expected 413")
+        return False
+    if corrupt.status_code != 400 or "error" not in corrupt.get_json():
+        print(f"✗ Corrupt gzip body returned {corrupt.status_code}, expected 400")
+        return False
+
+    print("✓ gzip and deflate bodies handled; a 10MB zip bomb was refused with 413")
+    return True
+
+def test_legacy_function_call() -> bool:
+    """Test that LEGACY_FUNCTION_CALL returns the deprecated function_call shape."""
+    print("\nTesting legacy function_call toggle...")
+
+    client = local_client()
+    function = {"name": "lookup", "parameters": {"type": "object",
+                                                 "properties": {"query": {"type": "string"}}}}
+    payload = {
+        "model": "gpt-4",
+        "messages": [{"role": "user", "content": "Find it"}],
+        "tools": [{"type": "function", "function": function}],
+        "tool_choice": "required"
+    }
+
+    with override_config(TIMING_SETTINGS=NO_DELAY_TIMINGS, LEGACY_FUNCTION_CALL=True):
+        modern_request = client.post('/v1/chat/completions', headers=LOCAL_HEADERS, json=payload).get_json()
+        legacy_request = client.post('/v1/chat/completions', headers=LOCAL_HEADERS, json={
+            "model": "gpt-4",
+            "messages": [{"role": "user", "content": "Find it"}],
+            "functions": [function],
+            "function_call": {"name": "lookup"}
+        }).get_json()
+    with override_config(TIMING_SETTINGS=NO_DELAY_TIMINGS):
+        default = client.post('/v1/chat/completions', headers=LOCAL_HEADERS, json=payload).get_json()
+
+    for data in (modern_request, legacy_request):
+        choice = data["choices"][0]
+        message = choice["message"]
+        if choice["finish_reason"] != "function_call" or "tool_calls" in message:
+            print(f"✗ Expected the legacy shape, got {choice}")
+            return False
+        if message["function_call"]["name"] != "lookup" or \
+                "query" not in json.loads(message["function_call"]["arguments"]):
+            print(f"✗ Unexpected function_call: {message['function_call']}")
+            return False
+
+    if default["choices"][0]["finish_reason"] != "tool_calls":
+        print("✗ Toggle off should keep the modern tool_calls shape")
+        return False
+
+    print("✓ Toggle returned message.function_call with finish_reason function_call")
+    return True
+
+def test_rate_limit_recovery() -> bool:
+    """Test that a request honouring the returned Retry-After succeeds."""
+    import app as app_module
+
+    print("\nTesting rate-limit recovery headers...")
+
+    client = local_client()
+    payload = {
+        "model": "gpt-3.5-turbo",
+        "messages": [{"role": "user", "content": "Hello!"}],
+        "max_tokens": 50
+    }
+    limits = {**app_module.config.RATE_LIMIT_SETTINGS, "tokens_per_minute": 6000}
+
+    with override_config(TIMING_SETTINGS=NO_DELAY_TIMINGS, RATE_LIMIT_ENABLED=True,
+                         RATE_LIMIT_SETTINGS=limits):
+        app_module.TOKEN_BUCKET.tokens = 200
+
+        limited = None
+        for _ in range(20):
+            response = client.post('/v1/chat/completions', headers=LOCAL_HEADERS, json=payload)
+            if response.status_code == 429:
+                limited = response
+                break
+        if limited is None:
+            print("✗ Never hit the tokens-per-minute limit")
+            return False
+
+        retry_after_ms = int(limited.headers['retry-after-ms'])
+        reset = limited.headers['x-ratelimit-reset-tokens']
+        if int(limited.headers['Retry-After']) < retry_after_ms / 1000 or not reset.endswith('s'):
+            print(f"✗ Inconsistent headers: {dict(limited.headers)}")
+            return False
+        if not 0 < retry_after_ms <= 1000:
+            print(f"✗ Retry-After of {retry_after_ms}ms doesn't match a 100 tokens/s refill")
+            return False
+
+        time.sleep(retry_after_ms / 1000)
+        retried = client.post('/v1/chat/completions', headers=LOCAL_HEADERS, json=payload)
+
+        # A negative max_tokens still reserves tokens rather than adding them
+        app_module.TOKEN_BUCKET.tokens = 1000
+        client.post('/v1/chat/completions', headers=LOCAL_HEADERS, json={**payload, "max_tokens": -100000})
+        after_negative = app_module.TOKEN_BUCKET.remaining()
+        app_module.TOKEN_BUCKET.tokens = 6000
+
+    if retried.status_code != 200:
+        print(f"✗ Retry after {retry_after_ms}ms still got {retried.status_code}")
+        return False
+
+    if after_negative > 2000:
+        print(f"✗ A negative max_tokens overfilled the bucket to {after_negative} tokens")
+        return False
+
+    print(f"✓ Retry after the advertised {retry_after_ms}ms succeeded (reset {reset})")
+    return True
+
+def test_stream_obfuscation() -> bool:
+    """Test that obfuscation padding appears on chunks without changing content."""
+    print("\nTesting stream obfuscation padding...")
+
+    client = local_client()
+    payload = {
+        "model": "gpt-3.5-turbo",
+        "messages": [{"role": "user", "content": "Hello!"}],
+        "stream": True,
+        "store": True
+    }
+
+    with override_config(TIMING_SETTINGS=NO_DELAY_TIMINGS, STREAM_OBFUSCATION=True):
+        events = parse_sse_events(client.post('/v1/chat/completions', headers=LOCAL_HEADERS,
+                                              json=payload).response)
+
+    chunks = [json.loads(e['data']) for e in events if e.get('data') != '[DONE]']
+    paddings = [c.get("obfuscation") for c in chunks]
+    if not all(isinstance(p, str) and p for p in paddings) or len(set(map(len, paddings))) < 2:
+        print("✗ Chunks are missing random-length obfuscation padding")
+        return False
+
+    stored = client.get(f'/v1/chat/completions/{chunks[0]["id"]}', headers=LOCAL_HEADERS).get_json()
+    streamed = ''.join(event_content(e) for e in events)
+    if streamed != stored["choices"][0]["message"]["content"]:
+        print("✗ Obfuscation changed the reconstructed content")
+        return False
+
+    print(f"✓ {len(chunks)} chunks carried obfuscation padding; content reconstructed intact")
+    return True
+
+def test_empty_content() -> bool:
+    """Test that an immediate stop yields a well-formed empty completion."""
+    print("\nTesting empty assistant content...")
+
+    client = local_client()
+    payload = {
+        "model": "gpt-3.5-turbo",
+        "messages": [{"role": "user", "content": "Can you help?"}],
+        "max_tokens": 1,
+        "stop": ["I"]
+    }
+
+    with override_config(TIMING_SETTINGS=NO_DELAY_TIMINGS):
+        data = client.post('/v1/chat/completions', headers=LOCAL_HEADERS, json=payload).get_json()
+        events = parse_sse_events(client.post('/v1/chat/completions', headers=LOCAL_HEADERS,
+                                              json={**payload, "stream": True}).response)
+        # A stop that's neither a string nor an array is rejected even without STRICT_VALIDATION
+        mistyped = [client.post(route, headers=LOCAL_HEADERS, json={**payload, "models": ["gpt-4"], "stop": stop})
+                    for route in ('/v1/chat/completions', '/v1/compare') for stop in (5, True, 1.5)]
+
+    choice = data["choices"][0]
+    if any(response.status_code != 400 or response.get_json()["error"]["param"] != "stop"
+           or response.get_json()["error"]["type"] != "invalid_request_error" for response in mistyped):
+        print(f"✗ A mistyped stop was not a 400: {[response.status_code for response in mistyped]}")
+        return False
+    if choice["message"]["content"] != "" or choice["finish_reason"] != "stop" \
+            or data["usage"]["completion_tokens"] != 0:
+        print(f"✗ Unexpected non-streaming response: {choice}, usage {data['usage']}")
+        return False
+
+    chunks = [json.loads(e['data'])["choices"][0] for e in events if e.get('data') != '[DONE]']
+    if events[-1].get('data') != '[DONE]' or len(chunks) != 2:
+        print(f"✗ Expected a role chunk, a finish chunk and [DONE], got {len(events)} events")
+        return False
+    if chunks[0]["delta"] != {"role": "assistant", "content": ""} or chunks[1]["finish_reason"] != "stop":
+        print(f"✗ Unexpected stream chunks: {chunks}")
+        return False
+
+    print("✓ Empty completion was well-formed in both streaming and non-streaming modes")
+    return True
+
+def test_request_tokenizer() -> bool:
+    """Test that x-mock-tokenizer changes the usage reported for the same prompt."""
+    print("\nTesting per-request tokenizer selection...")
+
+    client = local_client()
+    payload = {
+        "model": "gpt-3.5-turbo",
+        "messages": [{"role": "user", "content": "Tokenizers disagree about antidisestablishmentarianism!"}],
+        "max_tokens": 0
+    }
+
+    import importlib.util
+    tokenizers = ["char"]
+    if importlib.util.find_spec("tiktoken"):
+        tokenizers += ["cl100k", "o200k"]
+    else:
+        print("  tiktoken is not installed; only checking the default and char tokenizers")
+
+    counts = {}
+    with override_config(TIMING_SETTINGS=NO_DELAY_TIMINGS):
+        for tokenizer in [None] + tokenizers:
+            headers = dict(LOCAL_HEADERS, **({"x-mock-tokenizer": tokenizer} if tokenizer else {}))
+            response = client.post('/v1/chat/completions', headers=headers, json=payload)
+            counts[tokenizer or "default"] = response.get_json()["usage"]["prompt_tokens"]
+        unknown = client.post('/v1/chat/completions', json=payload,
+                              headers=dict(LOCAL_HEADERS, **{"x-mock-tokenizer": "p50k"}))
+
+    if any(counts[tokenizer] == counts["default"] for tokenizer in tokenizers):
+        print(f"✗ A tokenizer reported the default prompt token count: {counts}")
+        return False
+    if unknown.status_code != 400 or unknown.get_json()["error"]["param"] != "x-mock-tokenizer":
+        print(f"✗ Expected a 400 for an unknown tokenizer, got {unknown.status_code}")
+        return False
+
+    print(f"✓ Prompt token counts differed per tokenizer: {counts}")
+    return True
+
+def test_response_language() -> bool:
+    """Test that x-mock-lang: ja produces only Japanese characters, streamed whole."""
+    import re
+    print("\nTesting x-mock-lang response language...")
+
+    client = local_client()
+    headers = dict(LOCAL_HEADERS, **{"x-mock-lang": "ja"})
+    payload = {
+        "model": "gpt-3.5-turbo",
+        "messages": [{"role": "user", "content": "Hello!"}]
+    }
+    japanese = re.compile(r'^[぀-ゟ゠-ヿ一-鿿。、]+$')
+
+    with override_config(TIMING_SETTINGS=NO_DELAY_TIMINGS):
+        data = client.post('/v1/chat/completions', headers=headers, json=payload).get_json()
+        events = parse_sse_events(client.post('/v1/chat/completions', headers=headers,
+                                              json={**payload, "stream": True}).response)
+        unknown = client.post('/v1/chat/completions', json=payload,
+                              headers=dict(LOCAL_HEADERS, **{"x-mock-lang": "xx"}))
+
+    content = data["choices"][0]["message"]["content"]
+    if not japanese.match(content):
+        print(f"✗ Non-Japanese characters in response: {content!r}")
+        return False
+
+    pieces = [event_content(e) for e in events]
+    if not japanese.match(''.join(pieces)) or any(len(piece) > 1 for piece in pieces):
+        print("✗ Streamed Japanese content was not split into whole characters")
+        return False
+    if unknown.status_code != 400:
+        print(f"✗ Expected a 400 for an unsupported language, got {unknown.status_code}")
+        return False
+
+    print(f"✓ Japanese response used only expected characters: {content}")
+    return True
+
+def test_organization_headers() -> bool:
+    """Test that ALLOWED_ORGS/ALLOWED_PROJECTS admit listed headers and deny others."""
+    print("\nTesting OpenAI-Organization / OpenAI-Project allow-lists...")
+
+    client = local_client()
+
+    def get_models(**headers):
+        return client.get('/v1/models', headers=dict(LOCAL_HEADERS, **headers))
+
+    with override_config(ALLOWED_ORGS=["org-allowed"], ALLOWED_PROJECTS=["proj_allowed"]):
+        allowed = get_models(**{"OpenAI-Organization": "org-allowed", "OpenAI-Project": "proj_allowed"})
+        no_headers = get_models()
+        bad_org = get_models(**{"OpenAI-Organization": "org-other"})
+        bad_project = get_models(**{"OpenAI-Organization": "org-allowed", "OpenAI-Project": "proj_other"})
+    with override_config(ALLOWED_ORGS=[], ALLOWED_PROJECTS=[]):
+        unrestricted = get_models(**{"OpenAI-Organization": "org-other"})
+
+    if allowed.status_code != 200 or no_headers.status_code != 200 or unrestricted.status_code != 200:
+        print(f"✗ Allowed requests failed: {allowed.status_code}, {no_headers.status_code}, "
+              f"{unrestricted.status_code}")
+        return False
+    if bad_org.status_code != 401 or bad_org.get_json()["error"]["code"] != "mismatched_organization":
+        print(f"✗ Disallowed organization got {bad_org.status_code}: {bad_org.get_json()}")
+        return False
+    if bad_project.status_code != 403 or bad_project.get_json()["error"]["code"] != "mismatched_project":
+        print(f"✗ Disallowed project got {bad_project.status_code}: {bad_project.get_json()}")
+        return False
+
+    print("✓ Allowed org/project headers passed; disallowed ones got 401/403")
+    return True
+
+def test_prompt_auto_truncation() -> bool:
+    """Test that AUTO_TRUNCATE drops the oldest messages and flags the response."""
+    print("\nTesting prompt auto-truncation...")
+
+    client = local_client()
+    messages = [{"role": "system", "content": "Be brief."}]
+    messages += [{"role": "user" if i % 2 == 0 else "assistant", "content": "word " * 10}
+                 for i in range(10)]
+    messages.append({"role": "user", "content": "And finally?"})
+    payload = {"model": "gpt-3.5-turbo", "messages": messages}
+
+    with override_config(TIMING_SETTINGS=NO_DELAY_TIMINGS, AUTO_TRUNCATE=True, PROMPT_TOKEN_LIMIT=30):
+        truncated = client.post('/v1/chat/completions', headers=LOCAL_HEADERS, json=payload)
+        within = client.post('/v1/chat/completions', headers=LOCAL_HEADERS,
+                             json={**payload, "messages": messages[-1:]})
+    with override_config(TIMING_SETTINGS=NO_DELAY_TIMINGS):
+        full = client.post('/v1/chat/completions', headers=LOCAL_HEADERS, json=payload)
+
+    prompt_tokens = truncated.get_json()["usage"]["prompt_tokens"]
+    # The system message (2) and final message (2) stay, plus two 10-word messages
+    if prompt_tokens != 24 or full.get_json()["usage"]["prompt_tokens"] != 104:
+        print(f"✗ Unexpected prompt_tokens: {prompt_tokens} truncated, "
+              f"{full.get_json()['usage']['prompt_tokens']} in full")
+        return False
+    if truncated.headers.get('x-mock-truncated') != 'true':
+        print("✗ Truncated response is missing x-mock-truncated: true")
+        return False
+    if 'x-mock-truncated' in within.headers or 'x-mock-truncated' in full.headers:
+        print("✗ Untruncated responses carried x-mock-truncated")
+        return False
+
+    print(f"✓ Over-limit prompt was truncated to {prompt_tokens} tokens and flagged")
+    return True
+
+def test_transcript_replay() -> bool:
+    """Test that a recorded transcript replays its events with the recorded timing."""
+    print("\nTesting SSE transcript replay...")
+
+    client = local_client()
+    fixtures = os.path.join(os.path.dirname(os.path.abspath(__file__)), 'fixtures')
+    headers = dict(LOCAL_HEADERS, **{"x-mock-transcript": "hello"})
+    payload = {
+        "model": "gpt-3.5-turbo",
+        "messages": [{"role": "user", "content": "Hello!"}],
+        "stream": True
+    }
+
+    with open(os.path.join(fixtures, 'hello.sse'), encoding='utf-8') as f:
+        recorded = [line for line in f.read().splitlines() if line.startswith('data: ')]
+
+    for scale, expected in ((1.0, 0.3), (2.0, 0.6)):
+        with override_config(SSE_TRANSCRIPT_DIR=fixtures, SSE_REPLAY_SCALE=scale):
+            started = time.monotonic()
+            body = b''.join(client.post('/v1/chat/completions', headers=headers,
+                                        json=payload).response).decode()
+            elapsed = time.monotonic() - started
+
+        replayed = [line for line in body.splitlines() if line.startswith('data: ')]
+        if replayed != recorded:
+            print(f"✗ Replayed events differ from the transcript at scale {scale}")
+            return False
+        if not expected * 0.9 <= elapsed <= expected + 0.25:
+            print(f"✗ Scale {scale} replay took {elapsed:.2f}s, expected about {expected}s")
+            return False
+
+    with override_config(SSE_TRANSCRIPT_DIR=fixtures):
+        missing = client.post('/v1/chat/completions', json=payload,
+                              headers=dict(LOCAL_HEADERS, **{"x-mock-transcript": "../app"}))
+    if missing.status_code != 400:
+        print(f"✗ Expected a 400 for an unknown transcript, got {missing.status_code}")
+        return False
+
+    print("✓ Transcript replayed exactly with its recorded timing at both scales")
+    return True
+
+def test_http10_streaming() -> bool:
+    """Test that HTTP/1.0 streaming requests are buffered or rejected per HTTP10_STREAM."""
+    print("\nTesting stream: true over HTTP/1.0...")
+
+    client = local_client()
+    payload = {
+        "model": "gpt-3.5-turbo",
+        "messages": [{"role": "user", "content": "Hello!"}],
+        "stream": True
+    }
+
+    def post():
+        return client.post('/v1/chat/completions', headers=LOCAL_HEADERS, json=payload,
+                           environ_overrides={'SERVER_PROTOCOL': 'HTTP/1.0'})
+
+    with override_config(TIMING_SETTINGS=NO_DELAY_TIMINGS, HTTP10_STREAM='buffer'):
+        buffered = post()
+    with override_config(TIMING_SETTINGS=NO_DELAY_TIMINGS, HTTP10_STREAM='error'):
+        rejected = post()
+
+    if buffered.status_code != 200 or \
+            buffered.get_json().get("object") != "chat.completion":
+        print(f"✗ Expected a buffered chat.completion, got {buffered.status_code} {buffered.mimetype}")
+        return False
+    if rejected.status_code != 400 or rejected.get_json()["error"]["param"] != "stream":
+        print(f"✗ Expected a 400 naming 'stream', got {rejected.status_code}")
+        return False
+
+    print("✓ HTTP/1.0 streaming requests were buffered or rejected as configured")
+    return True
+
+def test_strict_stream_negotiation() -> bool:
+    """Test that STRICT_STREAM_NEGOTIATION rejects Accept/stream mismatches both ways."""
+    print("\nTesting strict stream negotiation...")
+
+    client = local_client()
+    payload = {
+        "model": "gpt-3.5-turbo",
+        "messages": [{"role": "user", "content": "Hello!"}]
+    }
+
+    def post(accept: str, stream: bool):
+        return client.post('/v1/chat/completions', json={**payload, "stream": stream},
+                           headers=dict(LOCAL_HEADERS, Accept=accept))
+
+    with override_config(TIMING_SETTINGS=NO_DELAY_TIMINGS, STRICT_STREAM_NEGOTIATION=True):
+        sse_without_stream = post('text/event-stream', False)
+        stream_without_sse = post('application/json', True)
+        matched = [post('text/event-stream', True), post('application/json', False), post('*/*', True)]
+    with override_config(TIMING_SETTINGS=NO_DELAY_TIMINGS):
+        lenient = post('text/event-stream', False)
+
+    for name, response in (("Accept SSE, stream false", sse_without_stream),
+                           ("stream true, Accept JSON", stream_without_sse)):
+        error = response.get_json()["error"] if response.status_code == 400 else {}
+        if error.get("code") != "stream_negotiation_mismatch" or error.get("param") != "stream":
+            print(f"✗ {name}: expected a 400 mismatch error, got {response.status_code}")
+            return False
+    if any(response.status_code != 200 for response in matched) or lenient.status_code != 200:
+        print("✗ Consistent or lenient requests were rejected")
+        return False
+
+    print("✓ Both mismatch directions got a 400; consistent requests succeeded")
+    return True
+
+def test_model_comparison() -> bool:
+    """Test that /v1/compare returns one priced response per requested model."""
+    print("\nTesting /v1/compare...")
+
+    client = local_client()
+    payload = {
+        "models": ["gpt-3.5-turbo", "gpt-4"],
+        "messages": [{"role": "user", "content": "Hello!"}],
+        "max_tokens": 5
+    }
+
+    import app as app_module
+    limits = {**app_module.config.RATE_LIMIT_SETTINGS, "tokens_per_minute": 6000}
+
+    with override_config(TIMING_SETTINGS=NO_DELAY_TIMINGS):
+        response = client.post('/v1/compare', headers=LOCAL_HEADERS, json=payload)
+        missing = client.post('/v1/compare', headers=LOCAL_HEADERS,
+                              json={"messages": payload["messages"]})
+        # Models take chat's speed suffix and tokenizer header
+        suffixed = client.post('/v1/compare', headers={**LOCAL_HEADERS, "x-mock-tokenizer": "char"},
+                               json={**payload, "models": ["gpt-4@tps=50"], "max_tokens": 0})
+        bad_suffix = client.post('/v1/compare', headers=LOCAL_HEADERS,
+                                 json={**payload, "models": ["gpt-4@tps=0"]})
+        # Two models' reservations don't fit in a bucket either one alone would
+        with override_config(RATE_LIMIT_ENABLED=True, RATE_LIMIT_SETTINGS=limits):
+            app_module.TOKEN_BUCKET.tokens = 700
+            limited = client.post('/v1/compare', headers=LOCAL_HEADERS, json={**payload, "max_tokens": 500})
+            app_module.TOKEN_BUCKET.tokens = 6000
+
+    if response.status_code != 200:
+        print(f"✗ Comparison failed with {response.status_code}: {response.get_json()}")
+        return False
+
+    results = response.get_json()["data"]
+    if [r["model"] for r in results] != payload["models"] or \
+            [r["completion"]["model"] for r in results] != payload["models"]:
+        print(f"✗ Expected one response per model in order, got {[r['model'] for r in results]}")
+        return False
+
+    # gpt-4 costs 20-30x more per token, more than short replies can make up for
+    if not all(isinstance(r["cost"], float) for r in results) or results[1]["cost"] <= results[0]["cost"]:
+        print(f"✗ Per-model pricing looks wrong: {[r['cost'] for r in results]}")
+        return False
+    if missing.status_code != 400 or missing.get_json()["error"]["param"] != "models":
+        print(f"✗ Expected a 400 naming 'models', got {missing.status_code}")
+        return False
+
+    completion = suffixed.get_json()["data"][0]["completion"] if suffixed.status_code == 200 else {}
+    if completion.get("model") != "gpt-4" or \
+            completion["usage"]["prompt_tokens"] != len(payload["messages"][0]["content"]):
+        print(f"✗ The speed suffix or tokenizer header was not applied: {suffixed.get_json()}")
+        return False
+    if bad_suffix.status_code != 400 or bad_suffix.get_json()["error"]["param"] != "models":
+        print(f"✗ An invalid speed suffix was not a 400 naming 'models': {bad_suffix.status_code}")
+        return False
+    if limited.status_code != 429 or limited.get_json()["error"]["code"] != "rate_limit_exceeded":
+        print(f"✗ Comparing past the token budget was not rate limited: {limited.status_code}")
+        return False
+
+    print("✓ Got one priced response per model with matching model fields, within the token budget")
+    return True
+
+def test_response_templates() -> bool:
+    """Test that RESPONSE_TEMPLATE fills request variables, surviving JSON escaping."""
+    print("\nTesting response templating...")
+
+    client = local_client()
+    message = 'Say "hi" \\ then a newline\nand a tab\t… {{model}}'
+    payload = {
+        "model": "gpt-3.5-turbo",
+        "user": "Ada",
+        "messages": [{"role": "user", "content": message}]
+    }
+    expected = f"Hello Ada, you said {message} {{{{unknown}}}}"
+
+    with override_config(TIMING_SETTINGS=NO_DELAY_TIMINGS,
+                         RESPONSE_TEMPLATE="Hello {{user_name}}, you said {{last_message}} {{unknown}}"):
+        response = client.post('/v1/chat/completions', headers=LOCAL_HEADERS, json=payload)
+        events = parse_sse_events(client.post('/v1/chat/completions', headers=LOCAL_HEADERS,
+                                              json={**payload, "stream": True}).response)
+
+    content = json.loads(response.get_data(as_text=True))["choices"][0]["message"]["content"]
+    if content != expected:
+        print(f"✗ Expected {expected!r}, got {content!r}")
+        return False
+    if ''.join(event_content(e) for e in events) != expected:
+        print("✗ Streamed template content did not reconstruct exactly")
+        return False
+
+    print("✓ Template variables were substituted verbatim in both response modes")
+    return True
+
+def test_global_sse_event_rate() -> bool:
+    """Test that concurrent streams together stay near MAX_SSE_EVENTS_PER_SEC."""
+    import threading
+
+    print("\nTesting global SSE event rate cap...")
+
+    payload = {
+        "model": "gpt-3.5-turbo",
+        "messages": [{"role": "user", "content": "Hello!"}],
+        "stream": True
+    }
+    counts = []
+
+    def stream():
+        response = local_client().post('/v1/chat/completions', headers=LOCAL_HEADERS, json=payload)
+        counts.append(len(parse_sse_events(response.response)))
+
+    with override_config(TIMING_SETTINGS=NO_DELAY_TIMINGS, MAX_SSE_EVENTS_PER_SEC=400):
+        threads = [threading.Thread(target=stream) for _ in range(4)]
+        started = time.monotonic()
+        for thread in threads:
+            thread.start()
+        for thread in threads:
+            thread.join()
+        elapsed = time.monotonic() - started
+
+    rate = sum(counts) / elapsed
+    if not 300 <= rate <= 420:
+        print(f"✗ {sum(counts)} events in {elapsed:.2f}s is {rate:.0f}/s, expected about 400/s")
+        return False
+
+    print(f"✓ 4 streams sent {sum(counts)} events at {rate:.0f}/s against a 400/s cap")
+    return True
+
+def test_streaming_usage_with_n() -> bool:
+    """Test that the include_usage chunk sums completion tokens over all n choices."""
+    print("\nTesting streamed usage with n > 1...")
+
+    client = local_client()
+    payload = {
+        "model": "gpt-3.5-turbo",
+        "messages": [{"role": "user", "content": "Hello!"}],
+        "n": 2,
+        "stream": True,
+        "stream_options": {"include_usage": True}
+    }
+
+    with override_config(TIMING_SETTINGS=NO_DELAY_TIMINGS):
+        events = parse_sse_events(client.post('/v1/chat/completions', headers=LOCAL_HEADERS,
+                                              json=payload).response)
+
+    chunks = [json.loads(e['data']) for e in events if e.get('data') != '[DONE]']
+    contents = {0: '', 1: ''}
+    for chunk in chunks[:-1]:
+        choice = chunk["choices"][0]
+        contents[choice["index"]] += choice["delta"].get("content") or ''
+        if chunk.get("usage", "missing") is not None:
+            print("✗ Non-terminal chunks should carry usage: null")
+            return False
+
+    usage_chunk = chunks[-1]
+    if usage_chunk["choices"] != [] or not usage_chunk.get("usage"):
+        print(f"✗ Expected a terminal usage chunk with no choices, got {usage_chunk}")
+        return False
+
+    delivered = sum(len(content.split()) for content in contents.values())
+    if not all(contents.values()) or usage_chunk["usage"]["completion_tokens"] != delivered:
+        print(f"✗ usage.completion_tokens={usage_chunk['usage']['completion_tokens']}, "
+              f"but both choices delivered {delivered} tokens")
+        return False
+
+    print(f"✓ Usage chunk counted {delivered} completion tokens across both choices")
+    return True
+
+def test_content_checksum() -> bool:
+    """Test that x-mock-content-sha256 matches the body, and the stream's content."""
+    import hashlib
+    print("\nTesting content checksums...")
+
+    client = local_client()
+    payload = {
+        "model": "gpt-3.5-turbo",
+        "messages": [{"role": "user", "content": "Hello!"}]
+    }
+
+    with override_config(TIMING_SETTINGS=NO_DELAY_TIMINGS, CONTENT_CHECKSUM=True):
+        response = client.post('/v1/chat/completions', headers=LOCAL_HEADERS, json=payload)
+        body = b''.join(client.post('/v1/chat/completions', headers=LOCAL_HEADERS,
+                                    json={**payload, "stream": True}).response).decode()
+    with override_config(TIMING_SETTINGS=NO_DELAY_TIMINGS):
+        unchecked = client.post('/v1/chat/completions', headers=LOCAL_HEADERS, json=payload)
+
+    if response.headers.get('x-mock-content-sha256') != hashlib.sha256(response.get_data()).hexdigest():
+        print("✗ x-mock-content-sha256 does not match the response body")
+        return False
+    if 'x-mock-content-sha256' in unchecked.headers:
+        print("✗ Checksum header was sent with CONTENT_CHECKSUM off")
+        return False
+
+    content = ''.join(event_content(e) for e in parse_sse_events([body]))
+    expected = f": x-mock-content-sha256={hashlib.sha256(content.encode()).hexdigest()}"
+    if expected not in body.split('\n'):
+        print("✗ Stream is missing a checksum comment matching its content")
+        return False
+
+    print("✓ Checksums matched the response body and the streamed content")
+    return True
+
+def test_legacy_engines_path() -> bool:
+    """Test that /v1/engines/{engine}/completions returns a legacy text completion."""
+    print("\nTesting the legacy engines completions path...")
+
+    client = local_client()
+    payload = {"prompt": "Once upon a time", "max_tokens": 5}
+
+    with override_config(TIMING_SETTINGS=NO_DELAY_TIMINGS, LEGACY_ENGINES=True):
+        response = client.post('/v1/engines/davinci/completions', headers=LOCAL_HEADERS, json=payload)
+    with override_config(TIMING_SETTINGS=NO_DELAY_TIMINGS):
+        disabled = client.post('/v1/engines/davinci/completions', headers=LOCAL_HEADERS, json=payload)
+
+    data = response.get_json()
+    if response.status_code != 200 or data.get("object") != "text_completion" or data.get("model") != "davinci":
+        print(f"✗ Expected a davinci text_completion, got {response.status_code}: {data}")
+        return False
+
+    choice = data["choices"][0]
+    if not isinstance(choice.get("text"), str) or not choice["text"] or len(choice["text"].split()) > 5 \
+            or "logprobs" not in choice or choice.get("finish_reason") not in ("stop", "length"):
+        print(f"✗ Malformed legacy choice: {choice}")
+        return False
+    if data["usage"]["total_tokens"] != data["usage"]["prompt_tokens"] + data["usage"]["completion_tokens"]:
+        print(f"✗ Inconsistent usage: {data['usage']}")
+        return False
+    if disabled.status_code != 404:
+        print(f"✗ Engines path should 404 without LEGACY_ENGINES, got {disabled.status_code}")
+        return False
+
+    print(f"✓ Engines path returned a legacy completion: {choice['text']!r}")
+    return True
+
+def test_duplicated_stream_chunks() -> bool:
+    """Test that x-mock-duplicate-rate repeats some chunks and the stream still ends normally."""
+    print("\nTesting duplicated stream chunks...")
+
+    client = local_client()
+    headers = dict(LOCAL_HEADERS, **{"x-mock-duplicate-rate": "0.3"})
+    payload = {
+        "model": "gpt-3.5-turbo",
+        "messages": [{"role": "user", "content": "Hello!"}],
+        "stream": True,
+        "store": True,
+        "seed": 7
+    }
+
+    # Event ids tell a duplicate apart from a repeated character
+    with override_config(TIMING_SETTINGS=NO_DELAY_TIMINGS, STREAM_EVENT_IDS=True):
+        runs = [parse_sse_events(client.post('/v1/chat/completions', headers=headers,
+                                             json=payload).response) for _ in range(2)]
+        # The seed is checked before the stream starts, not when the generator first needs it
+        bad_seeds = [client.post('/v1/chat/completions', headers=headers, json={**payload, "seed": seed})
+                     for seed in ([1], {}, "7", 1.5, True)]
+
+    if any(response.status_code != 400 or response.get_json()["error"]["param"] != "seed"
+           for response in bad_seeds):
+        print(f"✗ A non-integer seed was not a 400: {[response.status_code for response in bad_seeds]}")
+        return False
+
+    events = runs[0]
+    duplicated = sum(1 for a, b in zip(events, events[1:]) if a == b)
+    if not duplicated or events[-1].get('data') != '[DONE]':
+        print(f"✗ Expected duplicated chunks and a normal [DONE], got {duplicated} duplicates")
+        return False
+
+    first = json.loads(events[0]['data'])
+    stored = client.get(f'/v1/chat/completions/{first["id"]}', headers=LOCAL_HEADERS).get_json()
+    finish = json.loads(events[-2]['data'])["choices"][0]["finish_reason"]
+    if finish != stored["choices"][0]["finish_reason"]:
+        print(f"✗ Stream ended with finish_reason {finish!r}")
+        return False
+
+    deduped = ''.join(event_content(e) for i, e in enumerate(events) if i == 0 or e != events[i - 1])
+    if deduped != stored["choices"][0]["message"]["content"] or \
+            stored["usage"]["completion_tokens"] != len(deduped.split()):
+        print("✗ Duplicates leaked into the authoritative content or usage")
+        return False
+
+    second = sum(1 for a, b in zip(runs[1], runs[1][1:]) if a == b)
+    print(f"✓ {duplicated} chunks were duplicated ({second} on a second seeded run); stream ended normally")
+    return True
+
+def test_json_number_formatting() -> bool:
+    """Test that JSON_NUMBER_FORMAT=decimal writes logprob floats without exponents."""
+    import re
+    print("\nTesting JSON number formatting...")
+
+    client = local_client()
+    payload = {
+        "model": "gpt-3.5-turbo",
+        "messages": [{"role": "user", "content": "Can you help?"}],
+        "logprobs": True,
+        "top_logprobs": 2
+    }
+    number = re.compile(r'"logprob": (-?[0-9][0-9.eE+-]*)')
+
+    with override_config(TIMING_SETTINGS=NO_DELAY_TIMINGS, JSON_NUMBER_FORMAT='decimal'):
+        body = client.post('/v1/chat/completions', headers=LOCAL_HEADERS, json=payload).get_data(as_text=True)
+
+    literals = number.findall(re.sub(r'":', '": ', body))
+    if not literals:
+        print("✗ Response carried no logprobs")
+        return False
+    if any(not re.fullmatch(r'-?\d+\.\d+', literal) for literal in literals):
+        print(f"✗ Logprobs not in plain decimal notation: {[l for l in literals if 'e' in l.lower()][:3]}")
+        return False
+
+    data = json.loads(body)
+    if not isinstance(data["usage"]["total_tokens"], int) or \
+            any(float(literal) >= 0 for literal in literals):
+        print("✗ Decimal formatting changed value types or signs")
+        return False
+    alternatives = [[alt["token"] for alt in entry["top_logprobs"]]
+                    for entry in data["choices"][0]["logprobs"]["content"]]
+    if any(len(set(tokens)) != 2 for tokens in alternatives):
+        print(f"✗ Expected 2 distinct top_logprobs per token, got {alternatives}")
+        return False
+
+    for extra in ({"top_logprobs": -3}, {"top_logprobs": 21}, {"top_logprobs": 1.5},
+                  {"logprobs": False}, {"logprobs": None}):
+        response = client.post('/v1/chat/completions', headers=LOCAL_HEADERS, json={**payload, **extra})
+        if response.status_code != 400 or response.get_json()["error"]["param"] != "top_logprobs":
+            print(f"✗ top_logprobs with {extra} got {response.status_code}, expected a 400")
+            return False
+
+    print(f"✓ {len(literals)} logprobs serialized in decimal notation, e.g. {literals[0]}")
+    return True
+
+def test_slow_consumer_backpressure() -> bool:
+    """Test that a slowly read stream is logged and counted as backpressure."""
+    import re
+    print("\nTesting slow-consumer backpressure instrumentation...")
+
+    client = local_client()
+
+    def backpressure_total() -> int:
+        body = client.get('/metrics').get_data(as_text=True)
+        return int(re.search(r'^mock_stream_backpressure_total (\d+)$', body, re.M).group(1))
+
+    payload = {
+        "model": "gpt-3.5-turbo",
+        "messages": [{"role": "user", "content": "Hello!"}],
+        "stream": True
+    }
+    with capture_logs() as messages, \
+            override_config(TIMING_SETTINGS=NO_DELAY_TIMINGS, BACKPRESSURE_LOG_MS=30):
+        before = backpressure_total()
+        response = client.post('/v1/chat/completions', headers=LOCAL_HEADERS, json=payload)
+        first = None
+        for i, raw in enumerate(response.response):
+            first = first or json.loads(raw.decode().split('data: ', 1)[1])["id"]
+            if i < 3:
+                time.sleep(0.08)  # A reader that stalls on its first chunks
+        after = backpressure_total()
+
+    if after - before < 3:
+        print(f"✗ mock_stream_backpressure_total rose by {after - before}, expected at least 3")
+        return False
+    if not any(first in message and "slow consumer" in message for message in messages):
+        print("✗ No backpressure warning named the stream's session id")
+        return False
+
+    print(f"✓ Slow reads were counted ({after - before}) and logged for {first}")
+    return True
+
+def test_model_availability() -> bool:
+    """Test that unavailable and deprecated models fail while other models work."""
+    import datetime
+    print("\nTesting per-model availability...")
+
+    client = local_client()
+    now = datetime.datetime.now(datetime.timezone.utc)
+    minute = now.hour * 60 + now.minute
+    # Windows starting an hour before / after the current UTC minute
+    current = ((minute - 60) % 1440, (minute + 60) % 1440)
+    later = ((minute + 120) % 1440, (minute + 180) % 1440)
+
+    def status(model: str) -> int:
+        payload = {"model": model, "messages": [{"role": "user", "content": "Hello!"}]}
+        return client.post('/v1/chat/completions', headers=LOCAL_HEADERS, json=payload).status_code
+
+    with override_config(TIMING_SETTINGS=NO_DELAY_TIMINGS, MODEL_DEPRECATED=["gpt-3.5-turbo-0301"],
+                         MODEL_UNAVAILABLE={"gpt-4": None, "gpt-4o": current, "gpt-4-turbo": later}):
+        statuses = {model: status(model) for model in
+                    ("gpt-4", "gpt-4o", "gpt-4-turbo", "gpt-3.5-turbo-0301", "gpt-3.5-turbo")}
+        payload = {"model": "gpt-4o", "messages": [{"role": "user", "content": "Hello!"}]}
+        windowed = client.post('/v1/chat/completions', headers=LOCAL_HEADERS, json=payload)
+        # A model id that isn't a string can't be looked up in the availability settings
+        mistyped = [client.post('/v1/embeddings', headers=LOCAL_HEADERS, json={"model": model, "input": "Hi"})
+                    for model in ([1, "a"], {})]
+
+    if any(response.status_code != 400 or response.get_json()["error"]["param"] != "model"
+           for response in mistyped):
+        print(f"✗ A non-string embedding model was not a 400: {[response.status_code for response in mistyped]}")
+        return False
+
+    expected = {"gpt-4": 503, "gpt-4o": 503, "gpt-4-turbo": 200, "gpt-3.5-turbo-0301": 404, "gpt-3.5-turbo": 200}
+    if statuses != expected:
+        print(f"✗ Expected statuses {expected}, got {statuses}")
+        return False
+
+    retry_after = int(windowed.headers.get('Retry-After', 0))
+    if windowed.get_json()["error"]["code"] != "model_unavailable" or not 3540 <= retry_after <= 3600:
+        print(f"✗ Windowed outage should retry after the window ends, got Retry-After {retry_after}")
+        return False
+
+    print("✓ Unavailable models got 503s, the deprecated one a 404, and others succeeded")
+    return True
+
+def test_embedded_app_factory() -> bool:
+    """Test that build_app() returns a working app configured by its settings."""
+    import config
+    from app import build_app
+    print("\nTesting build_app() for embedding...")
+
+    def ping(client) -> str:
+        response = client.post('/v1/chat/completions', headers=LOCAL_HEADERS, json={
+            "model": "gpt-3.5-turbo",
+            "messages": [{"role": "user", "content": config.PING_MARKER}]
+        })
+        return response.get_json()["choices"][0]["message"]["content"]
+
+    original = config.PONG_RESPONSE
+    client = build_app({"PONG_RESPONSE": "embedded-pong"}).test_client()
+    other = build_app({"PONG_RESPONSE": "other-pong"}).test_client()
+    models = client.get('/v1/models', headers=LOCAL_HEADERS)
+    pongs = [ping(client), ping(other), ping(local_client())]
+    missing = client.get('/v1/nothing-here', headers=LOCAL_HEADERS)
+
+    if models.status_code != 200 or models.get_json()["object"] != "list":
+        print(f"✗ Embedded app failed to list models: {models.status_code}")
+        return False
+    if pongs != ["embedded-pong", "other-pong", original] or config.PONG_RESPONSE != original:
+        print(f"✗ build_app() settings were not kept to their own app: {pongs}")
+        return False
+    if missing.status_code != 404 or missing.get_json()["error"]["type"] != "not_found":
+        print("✗ Embedded app is missing the JSON error handlers")
+        return False
+
+    # Settings read through helpers and middleware apply to their app too
+    import gzip
+    chat = {"model": "gpt-3.5-turbo", "messages": [{"role": "user", "content": "Hello!"}]}
+    with override_config(TIMING_SETTINGS=NO_DELAY_TIMINGS):
+        short = build_app({"DEFAULT_MAX_TOKENS": 2, "MODEL_DEFAULT_MAX": {"gpt-3.5-turbo": 2}}).test_client() \
+            .post('/v1/chat/completions', headers=LOCAL_HEADERS, json=chat).get_json()
+        failing = build_app({"ERROR_RATE": 1.0}).test_client() \
+            .post('/v1/chat/completions', headers=LOCAL_HEADERS, json=chat)
+        too_large = build_app({"MAX_REQUEST_BYTES": 10}).test_client().post(
+            '/v1/chat/completions', data=gzip.compress(json.dumps(chat).encode()),
+            headers={**LOCAL_HEADERS, "Content-Type": "application/json", "Content-Encoding": "gzip"})
+    if short["usage"]["completion_tokens"] != 2 or short["choices"][0]["finish_reason"] != "length":
+        print(f"✗ build_app() default max tokens were not applied: {short['usage']}")
+        return False
+    if failing.status_code < 400 or too_large.status_code != 413:
+        print(f"✗ build_app() ERROR_RATE or MAX_REQUEST_BYTES ignored: {failing.status_code}, {too_large.status_code}")
+        return False
+
+    import logging
+    level = logging.getLogger('app').level
+    try:
+        debug_level = build_app({"LOG_LEVEL": "DEBUG"}).logger.level
+    finally:
+        logging.getLogger('app').setLevel(level)
+    if debug_level != logging.DEBUG:
+        print(f"✗ build_app() LOG_LEVEL was overridden: {logging.getLevelName(debug_level)}")
+        return False
+
+    for bad in ({"NOT_A_SETTING": 1}, {"RATE_LIMIT_SETTINGS": {"tokens_per_minute": 1}}):
+        try:
+            build_app(bad)
+        except ValueError:
+            continue
+        print(f"✗ build_app() accepted a setting it can't apply: {bad}")
+        return False
+
+    # Signal handlers can't be installed off the main thread, so run() must skip them
+    import threading
+    import requests
+    from app import run
+    port, errors = free_port(), []
+
+    def serve():
+        try:
+            run('127.0.0.1', port)
+        except Exception as e:
+            errors.append(e)
+
+    threading.Thread(target=serve, daemon=True).start()
+    health = None
+    for _ in range(100):
+        try:
+            health = requests.get(f"http://127.0.0.1:{port}/health", timeout=1)
+            break
+        except requests.RequestException:
+            if errors:
+                break
+            time.sleep(0.05)
+    if errors or health is None or health.status_code != 200:
+        print(f"✗ run() failed on a background thread: {errors}")
+        return False
+
+    print("✓ build_app() served requests with its settings and rejected unknown ones; "
+          "run() served from a background thread")
+    return True
+
+def test_reasoning_segments() -> bool:
+    """Test that agent mode streams reasoning, then a tool call, then an answer."""
+    print("\nTesting reasoning → tool call → content segments...")
+
+    client = local_client()
+    tools = [{"type": "function", "function": {
+        "name": "get_weather",
+        "parameters": {"type": "object", "properties": {"location": {"type": "string"}}}
+    }}]
+    messages = [{"role": "user", "content": "What's the weather in Paris?"}]
+
+    def collect(messages: List[Dict[str, Any]]) -> List[Dict[str, Any]]:
+        payload = {"model": "gpt-4o", "messages": messages, "tools": tools, "stream": True,
+                   "stream_options": {"include_usage": True}}
+        events = parse_sse_events(client.post('/v1/chat/completions', headers=LOCAL_HEADERS,
+                                              json=payload).response)
+        return [json.loads(e['data']) for e in events if e.get('data') != '[DONE]']
+
+    def segments(chunks: List[Dict[str, Any]]) -> List[str]:
+        order = []
+        for chunk in chunks[:-2]:
+            delta = chunk["choices"][0]["delta"]
+            kind = next(k for k in ("reasoning_content", "tool_calls", "content") if delta.get(k))
+            if not order or order[-1] != kind:
+                order.append(kind)
+        return order
+
+    with override_config(TIMING_SETTINGS=NO_DELAY_TIMINGS, REASONING_SEGMENTS=True):
+        first = collect(messages)
+        call = next(c["choices"][0]["delta"]["tool_calls"][0] for c in first
+                    if c["choices"] and c["choices"][0]["delta"].get("tool_calls"))
+        second = collect(messages + [
+            {"role": "assistant", "content": None, "tool_calls": [
+                {"id": call["id"], "type": "function", "function": {"name": "get_weather", "arguments": "{}"}}]},
+            {"role": "tool", "tool_call_id": call["id"], "content": "Sunny, 21°C"}
+        ])
+
+    for name, chunks, expected_order, expected_finish in (
+            ("tool call turn", first, ["reasoning_content", "tool_calls"], "tool_calls"),
+            ("answer turn", second, ["reasoning_content", "content"], "stop")):
+        if chunks[0]["choices"][0]["delta"].get("role") != "assistant":
+            print(f"✗ {name}: first delta is missing the assistant role")
+            return False
+        if segments(chunks) != expected_order or chunks[-2]["choices"][0]["finish_reason"] != expected_finish:
+            print(f"✗ {name}: got segments {segments(chunks)}, "
+                  f"finish {chunks[-2]['choices'][0]['finish_reason']}")
+            return False
+
+        reasoning = ''.join(c["choices"][0]["delta"].get("reasoning_content", '') for c in chunks[:-1])
+        usage = chunks[-1]["usage"]
+        if usage["completion_tokens_details"]["reasoning_tokens"] != len(reasoning.split()) or \
+                usage["completion_tokens"] <= len(reasoning.split()):
+            print(f"✗ {name}: usage {usage} doesn't cover every segment")
+            return False
+
+    print("✓ Reasoning preceded the tool call, then the answer, with usage covering all segments")
+    return True
+
+def test_max_tokens_conflict() -> bool:
+    """Test that both token limits are rejected only for models configured to do so."""
+    print("\nTesting max_tokens vs max_completion_tokens...")
+
+    client = local_client()
+
+    def post(model: str):
+        return client.post('/v1/chat/completions', headers=LOCAL_HEADERS, json={
+            "model": model,
+            "messages": [{"role": "user", "content": "Hello!"}],
+            "max_tokens": 50,
+            "max_completion_tokens": 2
+        })
+
+    with override_config(TIMING_SETTINGS=NO_DELAY_TIMINGS, MAX_COMPLETION_TOKENS_ONLY_MODELS=["o1"]):
+        rejected = post("o1")
+        lenient = post("gpt-4o")
+
+    error = rejected.get_json().get("error", {})
+    if rejected.status_code != 400 or error.get("param") != "max_tokens" or \
+            "max_completion_tokens" not in error.get("message", ""):
+        print(f"✗ Expected a 400 for both limits on o1, got {rejected.status_code}: {error}")
+        return False
+    if lenient.status_code != 200 or lenient.get_json()["usage"]["completion_tokens"] > 2:
+        print("✗ Lenient model should accept both and honor max_completion_tokens")
+        return False
+
+    print("✓ Both limits were rejected for o1 and the newer one preferred elsewhere")
+    return True
+
+def test_structured_content_deltas() -> bool:
+    """Test that array-shaped content deltas rebuild the multi-part message."""
+    print("\nTesting structured streaming content...")
+
+    client = local_client()
+    payload = {
+        "model": "gpt-4o",
+        "messages": [{"role": "user", "content": [
+            {"type": "text", "text": "Please summarize this chart."},
+            {"type": "image_url", "image_url": {"url": "https://example.com/chart.png"}}
+        ]}],
+        "stream": True,
+        "store": True
+    }
+
+    with override_config(TIMING_SETTINGS=NO_DELAY_TIMINGS, MULTIPART_CONTENT='mirror'):
+        events = parse_sse_events(client.post('/v1/chat/completions', headers=LOCAL_HEADERS,
+                                              json=payload).response)
+
+    chunks = [json.loads(e['data']) for e in events if e.get('data') != '[DONE]']
+    parts = {}
+    for chunk in chunks:
+        content = chunk["choices"][0]["delta"].get("content")
+        if content is None:
+            continue
+        if not isinstance(content, list):
+            print(f"✗ Expected array-shaped content deltas, got {content!r}")
+            return False
+        for piece in content:
+            part = parts.setdefault(piece["index"], {"type": piece["type"], "text": "", "annotations": []})
+            part["text"] += piece.get("text", "")
+            part["annotations"] += piece.get("annotations", [])
+
+    stored = client.get(f'/v1/chat/completions/{chunks[0]["id"]}', headers=LOCAL_HEADERS).get_json()
+    expected = stored["choices"][0]["message"]["content"]
+    rebuilt = [parts[index] for index in sorted(parts)]
+    if not isinstance(expected, list) or rebuilt != expected:
+        print(f"✗ Rebuilt parts {rebuilt} differ from the message {expected}")
+        return False
+    if not expected[-1]["annotations"]:
+        print("✗ The last part carried no annotations")
+        return False
+
+    print(f"✓ {len(rebuilt)} streamed content part(s) rebuilt the multi-part message")
+    return True
+
+def test_service_tier_downgrade() -> bool:
+    """Test that service_tier is downgraded while the server is under load."""
+    print("\nTesting service tier downgrade...")
+    client = local_client()
+    payload = {
+        "model": "gpt-3.5-turbo",
+        "messages": [{"role": "user", "content": "Hello!"}],
+        "service_tier": "priority"
+    }
+
+    with override_config(SERVICE_TIER_DOWNGRADE_STREAMS=1, TIMING_SETTINGS=NO_DELAY_TIMINGS):
+        idle = client.post('/v1/chat/completions', headers=LOCAL_HEADERS, json=payload)
+
+        stream = client.post('/v1/chat/completions', headers=LOCAL_HEADERS,
+                             json={**payload, "stream": True})
+        next(iter(stream.response))
+        loaded = client.post('/v1/chat/completions', headers=LOCAL_HEADERS, json=payload)
+        stream.close()
+
+    if idle.get_json().get("service_tier") != "priority" or \
+            'x-mock-service-tier-downgrade' in idle.headers:
+        print(f"✗ Idle request was not served on the requested tier: {idle.get_json().get('service_tier')}")
+        return False
+
+    explanation = loaded.headers.get('x-mock-service-tier-downgrade', '')
+    if loaded.get_json().get("service_tier") != "default" or "reason=load" not in explanation:
+        print(f"✗ Loaded request was not downgraded: {loaded.get_json().get('service_tier')} "
+              f"({explanation or 'no header'})")
+        return False
+
+    for tier in ("turbo", 3, ["priority"]):
+        response = client.post('/v1/chat/completions', headers=LOCAL_HEADERS,
+                               json={**payload, "service_tier": tier})
+        if response.status_code != 400 or response.get_json()["error"]["param"] != "service_tier":
+            print(f"✗ service_tier {tier!r} got {response.status_code}: {response.get_json()}")
+            return False
+
+    print(f"✓ Served on 'default' instead of 'priority' under load ({explanation})")
+    return True
+
+def test_fixed_id() -> bool:
+    """Test that x-mock-fixed-id forces the same id across requests and stream chunks."""
+    print("\nTesting fixed response ids...")
+    client = local_client()
+    headers = {**LOCAL_HEADERS, "x-mock-fixed-id": "chatcmpl-dup"}
+    payload = {
+        "model": "gpt-3.5-turbo",
+        "messages": [{"role": "user", "content": "Hello!"}]
+    }
+
+    with override_config(TIMING_SETTINGS=NO_DELAY_TIMINGS):
+        ids = [client.post('/v1/chat/completions', headers=headers, json=payload).get_json()["id"]
+               for _ in range(2)]
+        stream = client.post('/v1/chat/completions', headers=headers, json={**payload, "stream": True})
+        ids += [json.loads(event["data"])["id"] for event in parse_sse_events(stream.response)
+                if event.get("data", "[DONE]") != "[DONE]"]
+        unique = client.post('/v1/chat/completions', headers=LOCAL_HEADERS, json=payload).get_json()["id"]
+
+    if set(ids) != {"chatcmpl-dup"}:
+        print(f"✗ Expected every id to be chatcmpl-dup, got {sorted(set(ids))}")
+        return False
+    if unique == "chatcmpl-dup":
+        print("✗ Request without the header reused the fixed id")
+        return False
+
+    print(f"✓ {len(ids)} responses and chunks all carried id chatcmpl-dup")
+    return True
+
+def test_prediction() -> bool:
+    """Test that a prediction is partly accepted, reported in usage and streamed faster."""
+    print("\nTesting predicted outputs...")
+    client = local_client()
+    prediction = "The quick brown fox jumps over the lazy dog near the quiet river bank"
+    payload = {
+        "model": "gpt-3.5-turbo",
+        "messages": [{"role": "user", "content": "Hello!"}],
+        "prediction": {"type": "content", "content": prediction}
+    }
+    timings = {**NO_DELAY_TIMINGS, "streaming_chunk_delay": 0.01}
+
+    with override_config(PREDICTION_ACCEPT_RATE=0.5, TIMING_SETTINGS=timings):
+        data = client.post('/v1/chat/completions', headers=LOCAL_HEADERS, json=payload).get_json()
+        stream = client.post('/v1/chat/completions', headers=LOCAL_HEADERS,
+                             json={**payload, "stream": True})
+        gaps, last, content = [], time.monotonic(), ""
+        for raw in stream.response:
+            now = time.monotonic()
+            for event in parse_sse_events([raw]):
+                if event.get("data", "[DONE]") != "[DONE]":
+                    text = json.loads(event["data"])["choices"][0]["delta"].get("content") or ""
+                    if text:
+                        gaps.append((len(content) < len("The quick brown fox jumps over the"), now - last))
+                    content += text
+            last = now
+
+    details = data["usage"].get("completion_tokens_details", {})
+    accepted = "The quick brown fox jumps over the"
+    if not data["choices"][0]["message"]["content"].startswith(accepted) or \
+            details.get("accepted_prediction_tokens") != 7 or details.get("rejected_prediction_tokens") != 7:
+        print(f"✗ Unexpected prediction split: {details}")
+        return False
+
+    accepted_gaps = [gap for is_accepted, gap in gaps if is_accepted]
+    generated_gaps = [gap for is_accepted, gap in gaps if not is_accepted]
+    if sum(accepted_gaps) / len(accepted_gaps) >= sum(generated_gaps) / len(generated_gaps) / 2:
+        print("✗ Accepted prediction content did not stream faster than generated content")
+        return False
+
+    print("✓ 7 accepted / 7 rejected prediction tokens; accepted text streamed without delay")
+    return True
+
+def test_refiller_watchdog() -> bool:
+    """Test that the watchdog logs a dead token bucket refiller and restarts it."""
+    print("\nTesting token bucket refiller watchdog...")
+
+    from app import TOKEN_BUCKET
+
+    with capture_logs() as messages, \
+            override_config(RATE_LIMIT_REFILL_INTERVAL_MS=20, RATE_LIMIT_WATCHDOG=True,
+                            RATE_LIMIT_WATCHDOG_RESTART=True):
+        TOKEN_BUCKET.ensure_refiller()
+        time.sleep(0.15)
+        # Simulate the refiller dying: retire it without starting a replacement
+        dead = TOKEN_BUCKET.refiller
+        TOKEN_BUCKET.generation += 1
+        dead.join(1)
+        time.sleep(0.3)
+        recovered = TOKEN_BUCKET.refiller is not dead and TOKEN_BUCKET.refiller.is_alive()
+        fresh = time.monotonic() - TOKEN_BUCKET.last_refill < 0.1
+
+    if not any("refiller has not run" in message for message in messages):
+        print("✗ The watchdog did not log the dead refiller")
+        return False
+    if not (recovered and fresh):
+        print("✗ The watchdog did not restart the refiller")
+        return False
+
+    print("✓ The dead refiller was logged and replaced, and the bucket refills again")
+    return True
+
+def test_sse_garbage() -> bool:
+    """Test that x-mock-sse-garbage adds malformed lines a tolerant parser can skip."""
+    print("\nTesting malformed SSE line injection...")
+    client = local_client()
+    payload = {
+        "model": "gpt-3.5-turbo",
+        "messages": [{"role": "user", "content": "Hello!"}],
+        "stream": True,
+        "seed": 7
+    }
+
+    with override_config(TIMING_SETTINGS=NO_DELAY_TIMINGS):
+        response = client.post('/v1/chat/completions', json=payload,
+                               headers={**LOCAL_HEADERS, "x-mock-sse-garbage": "true"})
+        lines = ''.join(raw.decode('utf-8') for raw in response.response).split('\n')
+        bad_seed = client.post('/v1/chat/completions', json={**payload, "seed": [7]},
+                               headers={**LOCAL_HEADERS, "x-mock-sse-garbage": "true"})
+
+    if bad_seed.status_code != 400 or bad_seed.get_json()["error"]["param"] != "seed":
+        print(f"✗ A list seed was not rejected before streaming: {bad_seed.status_code}")
+        return False
+    garbage = [line for line in lines if line and not line.startswith('data: ')]
+    if not garbage:
+        print("✗ No malformed lines were emitted")
+        return False
+
+    events = parse_sse_events(line + '\n' for line in lines)
+    data = [event["data"] for event in events if "data" in event]
+    try:
+        chunks = [json.loads(item) for item in data[:-1]]
+    except json.JSONDecodeError as e:
+        print(f"✗ A valid event was corrupted by the garbage: {e}")
+        return False
+    if data[-1] != "[DONE]" or chunks[-1]["choices"][0]["finish_reason"] != "stop":
+        print("✗ The stream did not end with its finish chunk and [DONE]")
+        return False
+
+    print(f"✓ {len(garbage)} malformed lines skipped; {len(chunks)} valid chunks still parsed")
+    return True
+
+def test_lifetime_token_budget() -> bool:
+    """Test that requests fail with insufficient_quota once the lifetime budget is spent."""
+    print("\nTesting lifetime token budget...")
+    client = local_client()
+    payload = {
+        "model": "gpt-3.5-turbo",
+        "messages": [{"role": "user", "content": "Hello!"}],
+        "max_tokens": 10
+    }
+
+    generated = client.get('/stats').get_json()["generated_tokens"]
+    with override_config(TIMING_SETTINGS=NO_DELAY_TIMINGS, LIFETIME_TOKEN_BUDGET=generated + 25):
+        statuses = []
+        while len(statuses) < 10 and 429 not in statuses:
+            rejected = client.post('/v1/chat/completions', headers=LOCAL_HEADERS, json=payload)
+            statuses.append(rejected.status_code)
+        stats = client.get('/stats').get_json()
+        legacy = client.post('/v1/completions', headers=LOCAL_HEADERS,
+                             json={"model": "gpt-3.5-turbo-instruct", "prompt": "Hi"})
+
+    if len(statuses) < 3 or set(statuses[:-1]) != {200} or stats["remaining_budget"] != 0:
+        print(f"✗ Budget was not drained as expected: {statuses}, {stats}")
+        return False
+    for label, response in (("chat", rejected), ("completions", legacy)):
+        error = response.get_json().get("error", {})
+        if response.status_code != 429 or error.get("code") != "insufficient_quota":
+            print(f"✗ {label} request after the budget ran out got {response.status_code}: {error}")
+            return False
+
+    print(f"✓ Budget drained after {stats['generated_tokens'] - generated} tokens; "
+          "later requests got 429 insufficient_quota")
+    return True
+
+def test_model_speed_suffix() -> bool:
+    """Test that a `model@tps=N` suffix paces the stream and is stripped from `model`."""
+    print("\nTesting per-request speed from the model suffix...")
+    client = local_client()
+    payload = {
+        "model": "gpt-4@tps=50",
+        "messages": [{"role": "user", "content": "Hello!"}],
+        "max_tokens": 3,
+        "stream": True
+    }
+
+    with override_config(TIMING_SETTINGS=NO_DELAY_TIMINGS, THROUGHPUT_ALPHA=0):
+        started = time.monotonic()
+        response = client.post('/v1/chat/completions', headers=LOCAL_HEADERS, json=payload)
+        chunks = [json.loads(event["data"]) for event in parse_sse_events(response.response)
+                  if event.get("data", "[DONE]") != "[DONE]"]
+        elapsed = time.monotonic() - started
+        invalid = [client.post('/v1/chat/completions', headers=LOCAL_HEADERS, json={**payload, "model": model})
+                   for model in ("gpt-4@tps=fast", "gpt-4@tps=1e-300", "gpt-4@tps=0.001", "gpt-4@tps=20000")]
+        with override_config(ECHO_MODEL_SUFFIX=True):
+            echoed = client.post('/v1/chat/completions', headers=LOCAL_HEADERS,
+                                 json={**payload, "stream": False}).get_json()["model"]
+        with override_config(MAX_COMPLETION_TOKENS_ONLY_MODELS=["gpt-4"]):
+            both_limits = client.post('/v1/chat/completions', headers=LOCAL_HEADERS,
+                                      json={**payload, "stream": False, "max_completion_tokens": 3})
+
+    expected = (len(chunks) - 1) / 50
+    if elapsed < expected * 0.9:
+        print(f"✗ {len(chunks)} chunks took {elapsed:.2f}s, faster than 50 chunks/s allows")
+        return False
+    if {chunk["model"] for chunk in chunks} != {"gpt-4"} or echoed != "gpt-4@tps=50":
+        print(f"✗ Unexpected model echo: {chunks[0]['model']}, {echoed}")
+        return False
+    if any(response.status_code != 400 or response.get_json()["error"]["param"] != "model"
+           for response in invalid):
+        print(f"✗ A malformed or out-of-range suffix got {[response.status_code for response in invalid]}")
+        return False
+    if both_limits.status_code != 400 or both_limits.get_json()["error"]["param"] != "max_tokens":
+        print(f"✗ A suffixed model skipped the per-model max_tokens check ({both_limits.status_code})")
+        return False
+
+    print(f"✓ {len(chunks)} chunks paced over {elapsed:.2f}s at 50 tps; suffix stripped from model")
+    return True
+
+def test_legacy_logprobs() -> bool:
+    """Test the legacy completions logprobs arrays and their 0-5 validation."""
+    print("\nTesting legacy completions logprobs...")
+    client = local_client()
+    payload = {"model": "gpt-3.5-turbo-instruct", "prompt": "Once upon a time", "logprobs": 3}
+
+    with override_config(TIMING_SETTINGS=NO_DELAY_TIMINGS):
+        choice = client.post('/v1/completions', headers=LOCAL_HEADERS, json=payload).get_json()["choices"][0]
+        echoed = client.post('/v1/completions', headers=LOCAL_HEADERS,
+                             json={**payload, "echo": True}).get_json()["choices"][0]
+        invalid = [client.post('/v1/completions', headers=LOCAL_HEADERS,
+                               json={**payload, "logprobs": value}).status_code for value in (6, -1, True)]
+
+    logprobs = choice["logprobs"] or {}
+    arrays = [logprobs.get(key) or [] for key in ("tokens", "token_logprobs", "top_logprobs", "text_offset")]
+    if not arrays[0] or len({len(array) for array in arrays}) != 1:
+        print(f"✗ Logprobs arrays missing or misaligned: {[len(array) for array in arrays]}")
+        return False
+    offsets = logprobs["text_offset"]
+    if any(a >= b for a, b in zip(offsets, offsets[1:])) or not choice["text"].startswith(''.join(logprobs["tokens"])):
+        print(f"✗ text_offset is not monotonic or tokens don't rebuild the text: {offsets}")
+        return False
+    if offsets[0] != len(payload["prompt"]) or echoed["logprobs"]["text_offset"][0] != 0:
+        print(f"✗ text_offset should start after the prompt ({len(payload['prompt'])}) without echo "
+              f"and at 0 with it, got {offsets[0]} and {echoed['logprobs']['text_offset'][0]}")
+        return False
+    if any(len(top) != 3 for top in logprobs["top_logprobs"]):
+        print(f"✗ Expected 3 top_logprobs alternatives per token, got {[len(top) for top in logprobs['top_logprobs']]}")
+        return False
+    if invalid != [400, 400, 400]:
+        print(f"✗ Out-of-range logprobs got {invalid}")
+        return False
+
+    print(f"✓ {len(offsets)} aligned tokens with monotonic text_offset; out-of-range values rejected")
+    return True
+
+def test_error_schedule() -> bool:
+    """Test that ERROR_SCHEDULE outcomes are followed exactly and wrap around."""
+    print("\nTesting deterministic error schedule...")
+    client = local_client()
+    payload = {
+        "model": "gpt-3.5-turbo",
+        "messages": [{"role": "user", "content": "Hello!"}]
+    }
+    schedule = [None, None, 429, 500, None]
+
+    with override_config(TIMING_SETTINGS=NO_DELAY_TIMINGS, ERROR_SCHEDULE=schedule):
+        client.post('/scenario/reset')
+        statuses = [client.post('/v1/chat/completions', headers=LOCAL_HEADERS, json=payload).status_code
+                    for _ in range(7)]
+        client.post('/scenario/reset')
+        reset = [client.post('/v1/chat/completions', headers=LOCAL_HEADERS, json=payload).status_code
+                 for _ in range(3)]
+
+    expected = [200, 200, 429, 500, 200, 200, 200]
+    if statuses != expected:
+        print(f"✗ Expected {expected}, got {statuses}")
+        return False
+    if reset != [200, 200, 429]:
+        print(f"✗ After /scenario/reset expected [200, 200, 429], got {reset}")
+        return False
+
+    print(f"✓ Responses followed the schedule and wrapped around: {statuses}")
+    return True
+
+def test_trace_context() -> bool:
+    """Test that an incoming traceparent is logged as a span and echoed back."""
+    import logging
+    print("\nTesting trace context propagation...")
+
+    client = local_client()
+
+    trace_id, parent_id = "4bf92f3577b34da6a3ce929d0e0e4736", "00f067aa0ba902b7"
+    headers = {**LOCAL_HEADERS, "traceparent": f"00-{trace_id}-{parent_id}-01", "tracestate": "mock=1"}
+    payload = {"model": "gpt-3.5-turbo", "messages": [{"role": "user", "content": "Hello!"}]}
+    # Spans are logged at INFO
+    with capture_logs(logging.INFO) as messages, \
+            override_config(TIMING_SETTINGS=NO_DELAY_TIMINGS, TRACE_CONTEXT=True):
+        traced = client.post('/v1/chat/completions', headers=headers, json=payload)
+        fresh = client.post('/v1/chat/completions', headers=LOCAL_HEADERS, json=payload)
+    # The trace starts in the earliest hook, which must already see an app's own settings
+    from app import build_app
+    with override_config(TIMING_SETTINGS=NO_DELAY_TIMINGS):
+        embedded = build_app({"TRACE_CONTEXT": True}).test_client() \
+            .post('/v1/chat/completions', headers=headers, json=payload)
+
+    version, echoed_trace, span_id, flags = traced.headers.get('traceparent', '---').split('-')
+    if echoed_trace != trace_id or span_id == parent_id or traced.headers.get('tracestate') != "mock=1":
+        print(f"✗ Trace context not continued: {traced.headers.get('traceparent')}")
+        return False
+    if not any(f"trace_id={trace_id}" in message and f"parent_span_id={parent_id}" in message
+               for message in messages):
+        print("✗ No logged span carried the incoming trace id")
+        return False
+    if trace_id not in embedded.headers.get('traceparent', ''):
+        print("✗ build_app({'TRACE_CONTEXT': True}) did not continue the trace")
+        return False
+    if trace_id in fresh.headers.get('traceparent', trace_id):
+        print("✗ A request without traceparent did not start a new trace")
+        return False
+
+    print(f"✓ Trace {trace_id[:8]}… was logged as a child span and echoed back")
+    return True
+
+def test_stop_token_ids() -> bool:
+    """Test that a token-id prompt with stop ids halts before the matching token."""
+    print("\nTesting token-id prompts and stop ids...")
+    from app import token_id
+
+    client = local_client()
+    payload = {
+        "model": "gpt-3.5-turbo-instruct",
+        "prompt": [1820, 3290, 318],
+        "stop": [token_id("gamma")],
+        "max_tokens": 10
+    }
+
+    with override_config(TIMING_SETTINGS=NO_DELAY_TIMINGS, RESPONSE_TEMPLATE="alpha beta gamma delta"):
+        stopped = client.post('/v1/completions', headers=LOCAL_HEADERS, json=payload).get_json()
+        unmatched = client.post('/v1/completions', headers=LOCAL_HEADERS,
+                                json={**payload, "stop": [token_id("omega")]}).get_json()
+        too_many = client.post('/v1/completions', headers=LOCAL_HEADERS,
+                               json={**payload, "stop": [1, 2, 3, 4, 5]})
+
+    choice = stopped["choices"][0]
+    if choice["text"] != "alpha beta" or choice["finish_reason"] != "stop" or \
+            stopped["usage"]["completion_tokens"] != 2:
+        print(f"✗ Generation did not halt before 'gamma': {choice['text']!r}, {stopped['usage']}")
+        return False
+    if unmatched["choices"][0]["text"] != "alpha beta gamma delta":
+        print(f"✗ A stop id that never matches changed the text: {unmatched['choices'][0]['text']!r}")
+        return False
+    if too_many.status_code != 400 or too_many.get_json()["error"]["code"] != "array_above_max_length":
+        print(f"✗ Five stop ids got {too_many.status_code}")
+        return False
+
+    print("✓ Token-id prompt stopped at the 'gamma' token id; more than 4 stop ids rejected")
+    return True
+
+def test_echo_params() -> bool:
+    """Test that ECHO_PARAMS reports defaulted and clamped sampling parameters."""
+    print("\nTesting effective parameter echoing...")
+    client = local_client()
+    payload = {
+        "model": "gpt-4",
+        "messages": [{"role": "user", "content": "Hello!"}],
+        "temperature": 5,
+        "top_p": -1
+    }
+
+    with override_config(TIMING_SETTINGS=NO_DELAY_TIMINGS, ECHO_PARAMS=True,
+                         MODEL_DEFAULT_TEMPERATURE={"gpt-4": 0.2}):
+        clamped = client.post('/v1/chat/completions', headers=LOCAL_HEADERS, json=payload).get_json()
+        defaulted = client.post('/v1/chat/completions', headers=LOCAL_HEADERS,
+                                json={"model": "gpt-4", "messages": payload["messages"]}).get_json()
+    plain = client.post('/v1/chat/completions', headers=LOCAL_HEADERS, json=payload).get_json()
+
+    params = clamped.get("x_effective_params", {})
+    if params.get("temperature") != 2 or params.get("top_p") != 0:
+        print(f"✗ Out-of-range values were not shown clamped: {params}")
+        return False
+    defaults = defaulted.get("x_effective_params", {})
+    if defaults.get("temperature") != 0.2 or defaults.get("top_p") != 1 or defaults.get("n") != 1:
+        print(f"✗ Omitted values did not show their defaults: {defaults}")
+        return False
+    if "x_effective_params" in plain:
+        print("✗ x_effective_params was sent with ECHO_PARAMS off")
+        return False
+
+    print(f"✓ temperature 5 echoed as {params['temperature']}, omitted temperature as 0.2")
+    return True
+
+def test_sighup_reload() -> bool:
+    """Test that SIGHUP reloads CONFIG_FILE while an in-flight stream keeps its config."""
+    import signal
+    import tempfile
+    print("\nTesting config reload on SIGHUP...")
+
+    import app as app_module
+    client = local_client()
+    payload = {"model": "gpt-3.5-turbo", "messages": [{"role": "user", "content": "Hello!"}]}
+
+    def write_settings(template: str, checksum: bool):
+        with open(path, 'w', encoding='utf-8') as f:
+            f.write(f"# Reloaded by the SIGHUP test\nRESPONSE_TEMPLATE={template}\n"
+                    f"CONTENT_CHECKSUM={checksum}\n")
+
+    path = tempfile.mkstemp(suffix='.env')[1]
+    previous_handler = signal.signal(signal.SIGHUP, app_module.handle_sighup)
+    try:
+        write_settings("before reload", True)
+        os.environ['CONFIG_FILE'] = path
+        os.kill(os.getpid(), signal.SIGHUP)
+
+        stream = client.post('/v1/chat/completions', headers=LOCAL_HEADERS, json={**payload, "stream": True})
+        chunks = iter(stream.response)
+        first = next(chunks)
+
+        write_settings("after reload", False)
+        os.kill(os.getpid(), signal.SIGHUP)
+        reloaded = client.post('/v1/chat/completions', headers=LOCAL_HEADERS, json=payload)
+        rest = b''.join(chunks).decode('utf-8')
+
+        # Dropping a line from the file reverts that setting to its default
+        with open(path, 'w', encoding='utf-8') as f:
+            f.write("CONTENT_CHECKSUM=False\n")
+        os.kill(os.getpid(), signal.SIGHUP)
+        reverted = app_module.config.RESPONSE_TEMPLATE
+        leaked = 'RESPONSE_TEMPLATE' in os.environ
+    finally:
+        signal.signal(signal.SIGHUP, previous_handler)
+        os.environ.pop('CONFIG_FILE', None)
+        os.remove(path)
+        app_module.reload_config()
+
+    if reloaded.get_json()["choices"][0]["message"]["content"] != "after reload" or \
+            'x-mock-content-sha256' in reloaded.headers:
+        print("✗ A request after SIGHUP did not see the reloaded config")
+        return False
+    events = parse_sse_events([first.decode('utf-8') + rest])
+    content = ''.join(json.loads(e["data"])["choices"][0]["delta"].get("content") or ''
+                      for e in events if e.get("data", "[DONE]") != "[DONE]")
+    if content != "before reload" or "x-mock-content-sha256=" not in rest or not rest.endswith("[DONE]\n\n"):
+        print(f"✗ The in-flight stream did not finish under its original config: {content!r}")
+        return False
+
+    if reverted or leaked:
+        print(f"✗ A line removed from CONFIG_FILE kept its value: {reverted!r}")
+        return False
+
+    print("✓ New requests saw the reloaded file; the open stream finished on the old config")
+    return True
+
+def test_shuffle_choices() -> bool:
+    """Test that shuffled n>1 streams arrive out of index order but reassemble correctly."""
+    print("\nTesting shuffled choice delivery...")
+    client = local_client()
+    payload = {
+        "model": "gpt-3.5-turbo",
+        "messages": [{"role": "user", "content": "Hello!"}],
+        "n": 3,
+        "seed": 11,
+        "stream": True
+    }
+
+    def stream(headers):
+        response = client.post('/v1/chat/completions', headers=headers, json=payload)
+        return [json.loads(event["data"]) for event in parse_sse_events(response.response)
+                if event.get("data", "[DONE]") != "[DONE]"]
+
+    with override_config(TIMING_SETTINGS=NO_DELAY_TIMINGS, RESPONSE_TEMPLATE="choice text {{model}}"):
+        chunks = stream({**LOCAL_HEADERS, "x-mock-shuffle-choices": "true"})
+        ordered = stream(LOCAL_HEADERS)
+
+        # Without a seed, a resumed stream must continue the same shuffled order
+        unseeded = {k: v for k, v in payload.items() if k != "seed"}
+        shuffled_headers = {**LOCAL_HEADERS, "x-mock-shuffle-choices": "true"}
+        with override_config(STREAM_EVENT_IDS=True):
+            response = client.post('/v1/chat/completions', headers=shuffled_headers, json=unseeded)
+            first_events = []
+            for raw in response.response:
+                first_events.extend(parse_sse_events([raw]))
+                if len(first_events) == 5:
+                    break
+            response.close()
+            resumed = client.post('/v1/chat/completions', json=unseeded,
+                                  headers={**shuffled_headers, "Last-Event-ID": first_events[-1]["id"]})
+            resumed_events = parse_sse_events(resumed.response)
+        resumed_chunks = [json.loads(event["data"]) for event in first_events + resumed_events
+                          if event.get("data", "[DONE]") != "[DONE]"]
+        bad_seed = client.post('/v1/chat/completions', headers=shuffled_headers, json={**payload, "seed": {}})
+
+    def indexes(stream_chunks):
+        return [chunk["choices"][0]["index"] for chunk in stream_chunks if chunk["choices"]]
+
+    round_robin = [i % 3 for i in range(len(indexes(chunks)))]
+    if indexes(chunks)[:len(round_robin) - 3] == round_robin[:len(round_robin) - 3]:
+        print("✗ Shuffled chunks still arrived in round-robin order")
+        return False
+    if indexes(ordered)[:9] != round_robin[:9]:
+        print(f"✗ Unshuffled stream was not round-robin: {indexes(ordered)[:9]}")
+        return False
+
+    def reassemble(stream_chunks):
+        contents = {}
+        for chunk in stream_chunks:
+            for choice in chunk["choices"]:
+                contents[choice["index"]] = contents.get(choice["index"], '') + (choice["delta"].get("content") or '')
+        return contents
+
+    expected = {index: "choice text gpt-3.5-turbo" for index in range(3)}
+    if reassemble(chunks) != expected:
+        print(f"✗ Choices did not reassemble correctly: {reassemble(chunks)}")
+        return False
+    if reassemble(resumed_chunks) != expected:
+        print(f"✗ An unseeded resumed stream changed the shuffle order: {reassemble(resumed_chunks)}")
+        return False
+    if bad_seed.status_code != 400 or bad_seed.get_json()["error"]["param"] != "seed":
+        print(f"✗ An object seed was not rejected before shuffling: {bad_seed.status_code}")
+        return False
+
+    print(f"✓ Chunks arrived out of order ({indexes(chunks)[:8]}…) and all 3 choices reassembled")
+    return True
+
+def test_count_tokens_endpoint() -> bool:
+    """Test that /v1/messages/count_tokens matches the token estimator."""
+    print("\nTesting Anthropic-style count_tokens endpoint...")
+    from app import count_tokens
+
+    client = local_client()
+    payload = {
+        "model": "claude-sonnet-4",
+        "system": [{"type": "text", "text": "You are a terse assistant."}],
+        "messages": [
+            {"role": "user", "content": "How many tokens is this message?"},
+            {"role": "assistant", "content": [{"type": "text", "text": "Let me count them."}]}
+        ]
+    }
+    expected = sum(count_tokens(text) for text in
+                   ("You are a terse assistant.", "How many tokens is this message?", "Let me count them."))
+
+    response = client.post('/v1/messages/count_tokens', json=payload, headers={"x-api-key": "sk-dummy"})
+    unauthorized = client.post('/v1/messages/count_tokens', json=payload, headers={"x-api-key": "wrong"})
+    malformed = [client.post('/v1/messages/count_tokens', data=body, content_type='application/json',
+                             headers={"x-api-key": "sk-dummy"}) for body in ('{"model": ', '', '{}')]
+
+    if response.status_code != 200 or response.get_json() != {"input_tokens": expected}:
+        print(f"✗ Expected {{'input_tokens': {expected}}}, got {response.status_code} {response.get_json()}")
+        return False
+    if unauthorized.status_code != 401 or unauthorized.get_json().get("type") != "error":
+        print(f"✗ A wrong x-api-key got {unauthorized.status_code}")
+        return False
+    if any(bad.status_code != 400 or bad.get_json().get("type") != "error" or
+           bad.get_json()["error"]["type"] != "invalid_request_error" for bad in malformed):
+        print(f"✗ Bad bodies did not get Anthropic-shaped errors: {[bad.get_json() for bad in malformed]}")
+        return False
+
+    print(f"✓ input_tokens={expected} matches the estimator")
+    return True
+
+def test_stream_eager_flush() -> bool:
+    """Test that STREAM_EAGER_FLUSH sets TCP_NODELAY and stops a stream arriving in bursts."""
+    import socket
+    import threading
+    from flask import request
+    from werkzeug.serving import make_server
+
+    print("\nTesting eager stream flushing...")
+
+    os.environ.setdefault('ENVIRONMENT', 'testing')
+    from app import build_app
+
+    application = build_app()
+    nodelay = []
+
+    @application.after_request
+    def record_nodelay(response):
+        # Checked once the body is sent, after every other hook has run
+        sock = request.environ['werkzeug.socket']
+        response.call_on_close(lambda: nodelay.append(
+            bool(sock.getsockopt(socket.IPPROTO_TCP, socket.TCP_NODELAY))))
+        return response
+
+    server = make_server('127.0.0.1', 0, application, threaded=True)
+    threading.Thread(target=server.serve_forever, daemon=True).start()
+    body = json.dumps({"model": "gpt-3.5-turbo", "messages": [{"role": "user", "content": "Hi"}],
+                       "stream": True, "max_tokens": 60})
+    headers = ''.join(f"{name}: {value}\r\n" for name, value in LOCAL_HEADERS.items())
+    chunk_delay = 0.005
+    # A reader that delays its ACKs lets Nagle's algorithm hold small writes back,
+    # the buffering that clumps a default stream into bursts
+    delayed_acks = hasattr(socket, 'TCP_QUICKACK')
+
+    def read_stream(eager: bool) -> tuple[bytes, List[tuple[float, int]]]:
+        """The response headers and (arrival time, events) for every socket read."""
+        timings = {**NO_DELAY_TIMINGS, "streaming_chunk_delay": chunk_delay}
+        with override_config(STREAM_EAGER_FLUSH=eager, TIMING_SETTINGS=timings), \
+                socket.create_connection(('127.0.0.1', server.server_port), timeout=10) as sock:
+            sock.sendall(f"POST /v1/chat/completions HTTP/1.1\r\nHost: localhost\r\n{headers}"
+                         f"Content-Length: {len(body)}\r\nConnection: close\r\n\r\n{body}".encode())
+            raw, reads = b'', []
+            while True:
+                if delayed_acks:
+                    # Linux re-enables quick ACKs on its own, so keep switching them off
+                    sock.setsockopt(socket.IPPROTO_TCP, socket.TCP_QUICKACK, 0)
+                data = sock.recv(65536)
+                if not data:
+                    break
+                raw += data
+                reads.append((time.monotonic(), data.count(b'data: ')))
+        return raw.split(b'\r\n\r\n', 1)[0], [read for read in reads if read[1]]
+
+    try:
+        default_headers, default_reads = read_stream(False)
+        eager_headers, eager_reads = read_stream(True)
+    finally:
+        server.shutdown()
+
+    if nodelay != [False, True]:
+        print(f"✗ TCP_NODELAY was {nodelay} for default/eager streams, expected [False, True]")
+        return False
+    if b'X-Accel-Buffering: no' not in eager_headers or b'X-Accel-Buffering' in default_headers:
+        print("✗ X-Accel-Buffering: no was not sent only with STREAM_EAGER_FLUSH")
+        return False
+
+    def clumped(reads) -> int:
+        return sum(events > 1 for _, events in reads)
+
+    def largest_burst(reads) -> int:
+        return max(events for _, events in reads)
+
+    events = sum(count for _, count in eager_reads)
+    if clumped(eager_reads) > events // 10 or largest_burst(eager_reads) > 2:
+        print(f"✗ Eager flushing clumped {clumped(eager_reads)}/{len(eager_reads)} reads, "
+              f"up to {largest_burst(eager_reads)} events in one")
+        return False
+    # Behind delayed ACKs the default stream must visibly burst, and eager flushing must not
+    if delayed_acks and (largest_burst(default_reads) < 3 or clumped(eager_reads) >= clumped(default_reads)):
+        print(f"✗ Eager flushing did not reduce clumping: bursts of up to {largest_burst(eager_reads)} events "
+              f"vs {largest_burst(default_reads)} by default")
+        return False
+    gaps = sorted(later[0] - earlier[0] for earlier, later in zip(eager_reads, eager_reads[1:]))
+    median_gap = gaps[len(gaps) // 2]
+    if median_gap < chunk_delay / 2:
+        print(f"✗ Eager events arrived {median_gap * 1000:.1f}ms apart, expected about {chunk_delay * 1000:.0f}ms")
+        return False
+
+    print(f"✓ Eager stream set TCP_NODELAY; {events} events in {len(eager_reads)} reads, "
+          f"{median_gap * 1000:.1f}ms apart (default clumped {clumped(default_reads)} reads, "
+          f"up to {largest_burst(default_reads)} events in one)")
+    return True
+
+def test_sighup_broken_config() -> bool:
+    """Test that a SIGHUP with an unparsable CONFIG_FILE keeps the server on its old config."""
+    import signal
+    import tempfile
+    import requests
+
+    print("\nTesting config reload with a broken config file...")
+
+    path = tempfile.mkstemp(suffix='.env')[1]
+    with open(path, 'w', encoding='utf-8') as f:
+        f.write("RESPONSE_TEMPLATE=still serving\n")
+    process, port = start_server_process(CONFIG_FILE=path)
+    try:
+        with open(path, 'w', encoding='utf-8') as f:
+            f.write("RESPONSE_TEMPLATE=never loaded\nMIN_LATENCY_MS=abc\n")
+        process.send_signal(signal.SIGHUP)
+        time.sleep(0.3)
+        response = requests.post(
+            f"http://127.0.0.1:{port}/v1/chat/completions", headers=LOCAL_HEADERS,
+            json={"model": "gpt-3.5-turbo", "messages": [{"role": "user", "content": "Hi"}]}, timeout=10
+        )
+        alive = process.poll() is None
+    except requests.ConnectionError:
+        response, alive = None, False
+    finally:
+        process.send_signal(signal.SIGTERM)
+        output = process.communicate(timeout=10)[0]
+        os.remove(path)
+
+    if not alive or response is None:
+        print(f"✗ The server died on SIGHUP:\n{output[-500:]}")
+        return False
+    if response.json()["choices"][0]["message"]["content"] != "still serving":
+        print(f"✗ The broken config was partly applied: {response.json()['choices'][0]['message']['content']!r}")
+        return False
+    if "Config reload failed" not in output:
+        print("✗ The failed reload was not logged")
+        return False
+
+    print("✓ The server logged the bad MIN_LATENCY_MS and kept serving its old config")
+    return True
+
+def test_sighup_during_reload() -> bool:
+    """Test that a SIGHUP arriving mid-reload is merged instead of deadlocking the server."""
+    import signal
+    import tempfile
+    import requests
+
+    print("\nTesting a second SIGHUP during a reload...")
+
+    path = tempfile.mkstemp(suffix='.env')[1]
+    with open(path, 'w', encoding='utf-8') as f:
+        f.write("RESPONSE_TEMPLATE=startup\n")
+    process, port = start_server_process(CONFIG_FILE=path)
+    fifo = path + '.fifo'
+    try:
+        # Reading a FIFO blocks the first reload, holding the lock, until it's written
+        os.mkfifo(fifo)
+        os.replace(fifo, path)
+        process.send_signal(signal.SIGHUP)
+        time.sleep(0.3)
+        process.send_signal(signal.SIGHUP)
+        time.sleep(0.3)
+        try:
+            writer = os.open(path, os.O_WRONLY | os.O_NONBLOCK)
+        except OSError:
+            writer = None
+        if writer is not None:
+            with open(path + '.next', 'w', encoding='utf-8') as f:
+                f.write("RESPONSE_TEMPLATE=merged reload\n")
+            os.replace(path + '.next', path)
+            os.write(writer, b"RESPONSE_TEMPLATE=first reload\n")
+            os.close(writer)
+        response = requests.post(
+            f"http://127.0.0.1:{port}/v1/chat/completions", headers=LOCAL_HEADERS,
+            json={"model": "gpt-3.5-turbo", "messages": [{"role": "user", "content": "Hi"}]}, timeout=3
+        )
+    except requests.RequestException:
+        response = None
+    finally:
+        process.kill()
+        process.communicate(timeout=10)
+        os.remove(path)
+
+    if writer is None or response is None:
+        print("✗ The second SIGHUP deadlocked the server")
+        return False
+    content = response.json()["choices"][0]["message"]["content"]
+    if content != "merged reload":
+        print(f"✗ The signal that arrived mid-reload was dropped: {content!r}")
+        return False
+
+    print("✓ The second SIGHUP was merged into a follow-up reload")
+    return True
+
+def test_reload_during_parallel_requests() -> bool:
+    """Test that n>1 and /v1/compare workers keep the request's config across a reload."""
+    import tempfile
+    import threading
+    print("\nTesting config reload during parallel generation...")
+
+    import app as app_module
+    client = local_client()
+    messages = [{"role": "user", "content": "Hello!"}]
+    path = tempfile.mkstemp(suffix='.env')[1]
+    with open(path, 'w', encoding='utf-8') as f:
+        f.write("RESPONSE_TEMPLATE=after reload\n")
+
+    def reload_soon():
+        time.sleep(0.1)
+        os.environ['CONFIG_FILE'] = path
+        app_module.reload_config()
+
+    slow = {**NO_DELAY_TIMINGS, "chat_completion_min_delay": 0.3, "chat_completion_max_delay": 0.3}
+    contents = []
+    try:
+        for route, payload in (('/v1/chat/completions', {"model": "gpt-3.5-turbo", "messages": messages, "n": 3}),
+                               ('/v1/compare', {"models": ["gpt-4", "gpt-3.5-turbo"], "messages": messages})):
+            with override_config(RESPONSE_TEMPLATE="before reload", TIMING_SETTINGS=slow):
+                reloader = threading.Thread(target=reload_soon)
+                reloader.start()
+                data = client.post(route, headers=LOCAL_HEADERS, json=payload).get_json()
+                reloader.join()
+            choices = data["choices"] if "choices" in data else \
+                [result["completion"]["choices"][0] for result in data["data"]]
+            contents += [choice["message"]["content"] for choice in choices]
+            os.environ.pop('CONFIG_FILE', None)
+            app_module.reload_config()
+    finally:
+        os.environ.pop('CONFIG_FILE', None)
+        os.remove(path)
+        app_module.reload_config()
+
+    if set(contents) != {"before reload"}:
+        print(f"✗ Workers mixed configs during a reload: {contents}")
+        return False
+
+    print(f"✓ All {len(contents)} parallel candidates used the config their request started with")
+    return True
+
+def test_shutdown_drains_requests() -> bool:
+    """Test that SIGTERM waits for a non-streaming request that's still being processed."""
+    import signal
+    import threading
+    import requests
+
+    print("\nTesting shutdown drain of non-streaming requests...")
+
+    process, port = start_server_process(SHUTDOWN_GRACE_SECS="3", MIN_LATENCY_MS="1000")
+    result = {}
+
+    def send():
+        try:
+            result["response"] = requests.post(
+                f"http://127.0.0.1:{port}/v1/chat/completions", headers=LOCAL_HEADERS,
+                json={"model": "gpt-3.5-turbo", "messages": [{"role": "user", "content": "Hi"}]},
+                timeout=10
+            )
+        except requests.RequestException as e:
+            result["error"] = e
+
+    try:
+        sender = threading.Thread(target=send)
+        sender.start()
+        time.sleep(0.3)
+        process.send_signal(signal.SIGTERM)
+        sender.join(timeout=10)
+        output, _ = process.communicate(timeout=10)
+    finally:
+        if process.poll() is None:
+            process.kill()
+
+    response = result.get("response")
+    if response is None or response.status_code != 200:
+        print(f"✗ In-flight request was cut off by the shutdown: {result}")
+        return False
+    if "Drained 1 request(s), forcibly closed 0" not in output:
+        print(f"✗ Expected one drained request in the shutdown summary, got {output!r}")
+        return False
+
+    print("✓ Shutdown waited for the in-flight request to finish")
+    return True
+
+def test_script_logging() -> bool:
+    """Test that info logs reach the output when the server runs as `python app.py`."""
+    import signal
+    import requests
+
+    print("\nTesting logging under python app.py...")
+
+    process, port = start_server_process(ENVIRONMENT="production", TRACE_CONTEXT="true")
+    try:
+        response = requests.get(f"http://127.0.0.1:{port}/v1/models", headers=LOCAL_HEADERS, timeout=10)
+        process.send_signal(signal.SIGTERM)
+        output, _ = process.communicate(timeout=10)
+    finally:
+        if process.poll() is None:
+            process.kill()
+
+    if response.status_code != 200 or "span GET /v1/models" not in output:
+        print(f"✗ The request's span was not logged: {output!r}")
+        return False
+
+    print("✓ Info-level span logs were written by the script's server")
+    return True
+
+# (name, test function) pairs run by --local and after the live server tests
+FEATURE_TESTS = [
+    ("Stream Resumption", test_stream_resume_with_last_event_id),
+    ("Minimum Latency Floor", test_min_latency_floor),
+    ("Required Tool Choice", test_tool_choice_required),
+    ("Validation Error Params", test_validation_error_params),
+    ("Concurrent Throughput Degradation", test_concurrent_throughput_degradation),
+    ("Stored Completion ETag", test_stored_completion_etag),
+    ("Malformed Body Fuzzing", test_malformed_body_fuzzing),
+    ("Thinking Placeholders", test_thinking_placeholders),
+    ("Audio Modality", test_audio_modality),
+    ("Shutdown Grace Period", test_shutdown_grace_period),
+    ("Per-Route Error Rates", test_per_route_error_rates),
+    ("Parallel Candidates", test_parallel_candidates),
+    ("Ping Marker", test_ping_marker),
+    ("Per-Model Default Max Tokens", test_model_default_max_tokens),
+    ("Compressed Request Bodies", test_compressed_request_bodies),
+    ("Legacy Function Call", test_legacy_function_call),
+    ("Rate Limit Recovery", test_rate_limit_recovery),
+    ("Stream Obfuscation", test_stream_obfuscation),
+    ("Empty Content", test_empty_content),
+    ("Per-Request Tokenizer", test_request_tokenizer),
+    ("Response Language", test_response_language),
+    ("Organization Headers", test_organization_headers),
+    ("Prompt Auto-Truncation", test_prompt_auto_truncation),
+    ("Transcript Replay", test_transcript_replay),
+    ("HTTP/1.0 Streaming", test_http10_streaming),
+    ("Strict Stream Negotiation", test_strict_stream_negotiation),
+    ("Model Comparison", test_model_comparison),
+    ("Response Templates", test_response_templates),
+    ("Global SSE Event Rate", test_global_sse_event_rate),
+    ("Streaming Usage With n", test_streaming_usage_with_n),
+    ("Content Checksum", test_content_checksum),
+    ("Legacy Engines Path", test_legacy_engines_path),
+    ("Duplicated Stream Chunks", test_duplicated_stream_chunks),
+    ("JSON Number Formatting", test_json_number_formatting),
+    ("Slow Consumer Backpressure", test_slow_consumer_backpressure),
+    ("Model Availability", test_model_availability),
+    ("Embedded App Factory", test_embedded_app_factory),
+    ("Reasoning Segments", test_reasoning_segments),
+    ("Max Tokens Conflict", test_max_tokens_conflict),
+    ("Structured Content Deltas", test_structured_content_deltas),
+    ("Service Tier Downgrade", test_service_tier_downgrade),
+    ("Fixed Response ID", test_fixed_id),
+    ("Predicted Outputs", test_prediction),
+    ("Token Bucket Watchdog", test_refiller_watchdog),
+    ("Malformed SSE Lines", test_sse_garbage),
+    ("Lifetime Token Budget", test_lifetime_token_budget),
+    ("Model Speed Suffix", test_model_speed_suffix),
+    ("Legacy Completion Logprobs", test_legacy_logprobs),
+    ("Error Schedule", test_error_schedule),
+    ("Trace Context Propagation", test_trace_context),
+    ("Token-ID Stop Sequences", test_stop_token_ids),
+    ("Effective Params Echo", test_echo_params),
+    ("SIGHUP Config Reload", test_sighup_reload),
+    ("Shuffled Stream Choices", test_shuffle_choices),
+    ("Anthropic Count Tokens", test_count_tokens_endpoint),
+    ("Eager Stream Flushing", test_stream_eager_flush),
+    ("SIGHUP With A Broken Config", test_sighup_broken_config),
+    ("Reload During Parallel Requests", test_reload_during_parallel_requests),
+    ("SIGHUP During A Reload", test_sighup_during_reload),
+    ("Shutdown Drains Plain Requests", test_shutdown_drains_requests),
+    ("Logging When Run As A Script", test_script_logging),
+]
+
+def run_feature_tests() -> Dict[str, bool]:
+    """Run all in-process feature tests."""
+    results = {}
+    for name, test in FEATURE_TESTS:
+        try:
+            results[name] = test()
+        except Exception as e:
+            print(f"✗ {name} error: {e}")
+            results[name] = False
+    return results
+
 def print_summary(test_results: Dict[str, bool]):
     """Print a summary of all test results."""
     print("\n" + "=" * 60)
@@ -333,6 +3142,11 @@ def main():
     # Run tests
     test_results = {}
 
+    if '--local' in sys.argv[1:]:
+        test_results.update(run_feature_tests())
+        print_summary(test_results)
+        sys.exit(0 if all(test_results.values()) else 1)
+
     # Test imports first
     if not test_imports():
         print("\n❌ Setup failed. Please install required dependencies.")
@@ -353,6 +3167,7 @@ def main():
     test_results["Embeddings"] = test_embeddings()
     test_results["Streaming"] = test_streaming()
     test_results["Error Handling"] = test_error_handling()
+    test_results.update(run_feature_tests())
 
     # Print summary
     print_summary(test_results)